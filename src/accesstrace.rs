@@ -0,0 +1,139 @@
+use crate::Error;
+use std::io::{ErrorKind, Read, Write};
+
+/// Identifies an access trace file, and the version of the format.
+const MAGIC: &[u8; 8] = b"HPDTRC01";
+
+/// Writes a trace of the indexes accessed by a benchmark.
+///
+/// The format is an 8 byte magic value, the length of the accessed array as a little-endian u64,
+/// then each index as a little-endian u64. The array length allows replaying the trace to check
+/// that the indexes are in bounds.
+pub struct AccessTraceWriter<W: Write> {
+    w: W,
+}
+
+impl<W: Write> AccessTraceWriter<W> {
+    pub fn new(mut w: W, data_len: usize) -> Result<Self, Error> {
+        w.write_all(MAGIC)?;
+        w.write_all(&(data_len as u64).to_le_bytes())?;
+        Ok(Self { w })
+    }
+
+    pub fn write_indexes(&mut self, indexes: &[usize]) -> Result<(), Error> {
+        for &index in indexes {
+            self.w.write_all(&(index as u64).to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the trace and returns the underlying writer.
+    pub fn finish(mut self) -> Result<W, Error> {
+        self.w.flush()?;
+        Ok(self.w)
+    }
+}
+
+/// Reads a trace written by [`AccessTraceWriter`].
+pub struct AccessTraceReader<R: Read> {
+    r: R,
+    data_len: usize,
+}
+
+impl<R: Read> AccessTraceReader<R> {
+    pub fn new(mut r: R) -> Result<Self, Error> {
+        let mut header = [0u8; 16];
+        r.read_exact(&mut header)?;
+        if &header[..8] != MAGIC {
+            return Err(Error::parse("access trace", "invalid magic"));
+        }
+        let data_len = u64::from_le_bytes(header[8..].try_into().unwrap()) as usize;
+        Ok(Self { r, data_len })
+    }
+
+    /// Returns the length of the array the trace was recorded from.
+    #[must_use]
+    pub const fn data_len(&self) -> usize {
+        self.data_len
+    }
+
+    /// Reads the next indexes into indexes. Returns the number of indexes read, which is less
+    /// than `indexes.len()` only at the end of the trace.
+    pub fn read_indexes(&mut self, indexes: &mut [usize]) -> Result<usize, Error> {
+        for (count, index) in indexes.iter_mut().enumerate() {
+            let Some(value) = self.read_u64()? else {
+                return Ok(count);
+            };
+            if value >= self.data_len as u64 {
+                return Err(Error::parse(
+                    "access trace",
+                    format!("index {value} out of bounds for data_len={}", self.data_len),
+                ));
+            }
+            *index = value as usize;
+        }
+        Ok(indexes.len())
+    }
+
+    /// Returns the next u64, or None at the end of the trace.
+    fn read_u64(&mut self) -> Result<Option<u64>, Error> {
+        let mut bytes = [0u8; 8];
+        let mut filled = 0;
+        while filled < bytes.len() {
+            match self.r.read(&mut bytes[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        match filled {
+            0 => Ok(None),
+            8 => Ok(Some(u64::from_le_bytes(bytes))),
+            _ => Err(Error::parse("access trace", "truncated")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut writer = AccessTraceWriter::new(Vec::new(), 100).unwrap();
+        writer.write_indexes(&[0, 99, 42]).unwrap();
+        writer.write_indexes(&[7]).unwrap();
+        let trace = writer.finish().unwrap();
+        assert_eq!(16 + 4 * 8, trace.len());
+
+        let mut reader = AccessTraceReader::new(trace.as_slice()).unwrap();
+        assert_eq!(100, reader.data_len());
+        let mut indexes = [0; 3];
+        assert_eq!(3, reader.read_indexes(&mut indexes).unwrap());
+        assert_eq!([0, 99, 42], indexes);
+        assert_eq!(1, reader.read_indexes(&mut indexes).unwrap());
+        assert_eq!(7, indexes[0]);
+        assert_eq!(0, reader.read_indexes(&mut indexes).unwrap());
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(AccessTraceReader::new(&b"not a trace at all"[..]).is_err());
+
+        // index out of bounds
+        let mut writer = AccessTraceWriter::new(Vec::new(), 10).unwrap();
+        writer.write_indexes(&[10]).unwrap();
+        let trace = writer.finish().unwrap();
+        let mut reader = AccessTraceReader::new(trace.as_slice()).unwrap();
+        let mut indexes = [0; 1];
+        assert!(reader.read_indexes(&mut indexes).is_err());
+
+        // truncated index
+        let mut writer = AccessTraceWriter::new(Vec::new(), 10).unwrap();
+        writer.write_indexes(&[1]).unwrap();
+        let trace = writer.finish().unwrap();
+        let mut reader = AccessTraceReader::new(&trace[..trace.len() - 1]).unwrap();
+        assert!(reader.read_indexes(&mut indexes).is_err());
+    }
+}
@@ -1,4 +1,5 @@
-use nix::unistd::SysconfVar;
+#[cfg(any(test, target_os = "linux"))]
+use hugepagedemo::Error;
 #[cfg(any(test, target_os = "linux"))]
 use std::sync::LazyLock;
 
@@ -37,34 +38,110 @@ impl std::fmt::Display for HugepageSetting {
     }
 }
 
+/// The transparent hugepage setting for shared memory (shmem/tmpfs and memfd) from
+/// `/sys/kernel/mm/transparent_hugepage/shmem_enabled`, which is separate from the setting for
+/// anonymous memory.
+#[cfg(any(test, target_os = "linux"))]
+#[derive(PartialEq, Eq, Debug)]
+pub enum ShmemHugepageSetting {
+    Always,
+    WithinSize,
+    Advise,
+    Never,
+    Deny,
+    Force,
+}
+
 #[cfg(any(test, target_os = "linux"))]
-pub fn parse_hugepage_enabled(input: &[u8]) -> Result<HugepageSetting, String> {
+impl ShmemHugepageSetting {
+    fn from_bytes(input: &[u8]) -> Result<Self, String> {
+        match input {
+            b"always" => Ok(Self::Always),
+            b"within_size" => Ok(Self::WithinSize),
+            b"advise" => Ok(Self::Advise),
+            b"never" => Ok(Self::Never),
+            b"deny" => Ok(Self::Deny),
+            b"force" => Ok(Self::Force),
+            _ => Err(format!(
+                "unknown shmem_enabled setting {}",
+                String::from_utf8_lossy(input)
+            )),
+        }
+    }
+
+    /// Returns a description of when shared memory uses huge pages with this setting.
+    #[cfg(target_os = "linux")]
+    pub const fn description(&self) -> &'static str {
+        match self {
+            Self::Always => "always uses huge pages",
+            Self::WithinSize => "uses huge pages that fit within the file size",
+            Self::Advise => "only uses huge pages with madvise(MADV_HUGEPAGE)",
+            Self::Never => "never uses huge pages, even with madvise(MADV_HUGEPAGE)",
+            Self::Deny => "never uses huge pages, for testing",
+            Self::Force => "always uses huge pages, for testing",
+        }
+    }
+}
+
+#[cfg(any(test, target_os = "linux"))]
+impl std::fmt::Display for ShmemHugepageSetting {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Always => "always",
+            Self::WithinSize => "within_size",
+            Self::Advise => "advise",
+            Self::Never => "never",
+            Self::Deny => "deny",
+            Self::Force => "force",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// Returns the selected value from a sysfs setting that lists every value, with the selected one
+/// in square brackets like "always [madvise] never".
+#[cfg(any(test, target_os = "linux"))]
+pub fn parse_selected_setting(input: &[u8]) -> Result<&[u8], Error> {
     static RE: LazyLock<regex::bytes::Regex> =
         LazyLock::new(|| regex::bytes::Regex::new(r"\[([^\]]+)\]").unwrap());
 
     let string_matches = RE.captures(input);
     if string_matches.is_none() {
-        return Err(format!(
-            "could not match hugepages input: {}",
-            String::from_utf8_lossy(input)
+        return Err(Error::parse(
+            "hugepages setting",
+            format!(
+                "no selected value in brackets: {}",
+                String::from_utf8_lossy(input)
+            ),
         ));
     }
     let matched = string_matches.unwrap().get(1).unwrap();
+    Ok(matched.as_bytes())
+}
 
-    HugepageSetting::from_bytes(matched.as_bytes())
+#[cfg(any(test, target_os = "linux"))]
+pub fn parse_hugepage_enabled(input: &[u8]) -> Result<HugepageSetting, Error> {
+    HugepageSetting::from_bytes(parse_selected_setting(input)?)
+        .map_err(|msg| Error::parse("transparent_hugepage/enabled", msg))
+}
+
+#[cfg(any(test, target_os = "linux"))]
+pub fn parse_shmem_enabled(input: &[u8]) -> Result<ShmemHugepageSetting, Error> {
+    ShmemHugepageSetting::from_bytes(parse_selected_setting(input)?)
+        .map_err(|msg| Error::parse("transparent_hugepage/shmem_enabled", msg))
 }
 
-pub fn sysconf_page_size() -> usize {
-    let page_size = nix::unistd::sysconf(SysconfVar::PAGE_SIZE)
-        .expect("BUG: sysconf(_SC_PAGESIZE) must work")
-        .expect("BUG: page size must not be None");
-    assert!(page_size > 0, "page_size={page_size} must be > 0");
-    page_size as usize
+/// Parses the page size from a directory name in /sys/kernel/mm/hugepages, like
+/// "hugepages-2048kB". Returns None if the name does not match.
+#[cfg(any(test, target_os = "linux"))]
+pub fn parse_hugetlb_dir_name(name: &str) -> Option<usize> {
+    let kib = name.strip_prefix("hugepages-")?.strip_suffix("kB")?;
+    kib.parse::<usize>().ok().map(|kib| kib * 1024)
 }
 
 #[cfg(any(test, target_os = "linux"))]
 pub fn touch_pages(s: &mut [u64]) {
-    let page_size = sysconf_page_size();
+    let page_size = hugepagedemo::sysconf_page_size();
     println!("touch_pages with page_size={page_size}");
 
     // write a zero every stride elements, which should fault every page
@@ -74,6 +151,29 @@ pub fn touch_pages(s: &mut [u64]) {
     }
 }
 
+/// How the page size of an address was found, from most to least precise.
+#[derive(strum::Display, strum::EnumString, PartialEq, Eq, Debug, Clone, Copy)]
+pub enum PageSizeBackend {
+    /// The flags of the page frame in `/proc/kpageflags`, found with `/proc/self/pagemap`.
+    /// Requires root outside a container.
+    Kpageflags,
+    /// The `KernelPageSize` and huge page counters of the mapping in `/proc/self/smaps`. Does not
+    /// require root, but cannot tell which pages of a partially huge mapping are huge.
+    Smaps,
+    /// The base page size from sysconf, on platforms without a way to check.
+    Sysconf,
+}
+
+/// The advice passed to madvise for a mapping before it is faulted in.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum HugepageAdvice {
+    /// `madvise(MADV_HUGEPAGE)`: use transparent huge pages if the setting is madvise or always.
+    Hugepage,
+    /// `madvise(MADV_NOHUGEPAGE)`: never use transparent huge pages.
+    #[cfg(target_os = "linux")]
+    NoHugepage,
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -86,6 +186,16 @@ mod test {
         touch_pages(&mut v);
     }
 
+    #[test]
+    fn test_parse_hugetlb_dir_name() {
+        assert_eq!(Some(2 << 20), parse_hugetlb_dir_name("hugepages-2048kB"));
+        assert_eq!(Some(1 << 30), parse_hugetlb_dir_name("hugepages-1048576kB"));
+        assert_eq!(Some(64 << 10), parse_hugetlb_dir_name("hugepages-64kB"));
+        assert_eq!(None, parse_hugetlb_dir_name("hugepages-2048"));
+        assert_eq!(None, parse_hugetlb_dir_name("hugepages-xkB"));
+        assert_eq!(None, parse_hugetlb_dir_name("enabled"));
+    }
+
     #[test]
     fn test_parse_hugepage() {
         assert_eq!(
@@ -96,5 +206,20 @@ mod test {
             HugepageSetting::Never,
             parse_hugepage_enabled(b"always madvise [never]\n").unwrap()
         );
+        assert!(parse_hugepage_enabled(b"always madvise never\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_shmem_enabled() {
+        assert_eq!(
+            ShmemHugepageSetting::Never,
+            parse_shmem_enabled(b"always within_size advise [never] deny force\n").unwrap()
+        );
+        assert_eq!(
+            ShmemHugepageSetting::WithinSize,
+            parse_shmem_enabled(b"always [within_size] advise never deny force\n").unwrap()
+        );
+        // the per-size settings in hugepages-2048kB/shmem_enabled can also be inherit
+        assert!(parse_shmem_enabled(b"always [inherit] within_size advise never\n").is_err());
     }
 }
@@ -1,5 +1,5 @@
 use clap::Parser;
-use hugepagedemo::MmapRegion;
+use hugepagedemo::{KernelFs, MmapRegion, PageSizes, page_size_string, parse_duration};
 use std::{
     error::Error,
     time::{Duration, Instant},
@@ -11,30 +11,16 @@ use time::OffsetDateTime;
 #[command(version, about, long_about = None)]
 struct FaultLatencyOptions {
     /// probe the page latency at this interval.
-    #[arg(long, default_value = "1s", value_parser(clap_parse_go_duration))]
+    #[arg(long, default_value = "1s", value_parser(parse_duration))]
     test_interval: Duration,
 
     /// sleep duration between probing the different page sizes.
     // allow(dead_code) for Mac OS X where the option is unused
     //#[allow(dead_code)]
-    #[arg(long, default_value = "100ms", value_parser(clap_parse_go_duration))]
+    #[arg(long, default_value = "100ms", value_parser(parse_duration))]
     sleep_between_page_sizes: Duration,
 }
 
-/// Parses a duration using Go's formats, with the signature required by clap.
-fn clap_parse_go_duration(s: &str) -> Result<Duration, String> {
-    let result = go_parse_duration::parse_duration(s);
-    match result {
-        Err(err) => Err(format!("{err:?}")),
-        Ok(nanos) => {
-            assert!(nanos >= 0);
-            Ok(Duration::from_nanos(
-                nanos.try_into().expect("BUG: duration must be >= 0"),
-            ))
-        }
-    }
-}
-
 pub struct FaultLatency {
     mmap: Duration,
     fault: Duration,
@@ -51,11 +37,9 @@ impl FaultLatency {
     }
 }
 
-fn fault_4kib() -> Result<FaultLatency, nix::errno::Errno> {
-    const PAGE_4KIB: usize = 4 << 10;
-
+fn fault_base_page(base_page_size: usize) -> Result<FaultLatency, hugepagedemo::Error> {
     let start = Instant::now();
-    let region = MmapRegion::new(PAGE_4KIB)?;
+    let region = MmapRegion::new(base_page_size)?;
     let mmap_end = Instant::now();
     let u64_pointer = region.get_mut().cast::<u64>();
     unsafe {
@@ -77,6 +61,8 @@ fn fault_4kib() -> Result<FaultLatency, nix::errno::Errno> {
 #[allow(clippy::similar_names)]
 fn main() -> Result<(), Box<dyn Error>> {
     let config = FaultLatencyOptions::parse();
+    let page_sizes = PageSizes::read(&KernelFs::default())?;
+    let base_string = page_size_string(page_sizes.base);
 
     let mut next = Instant::now() + config.test_interval;
     loop {
@@ -85,30 +71,31 @@ fn main() -> Result<(), Box<dyn Error>> {
         std::thread::sleep(next - Instant::now());
         next += config.test_interval;
 
-        let timing_4kib = fault_4kib()?;
+        let timing_base = fault_base_page(page_sizes.base)?;
 
         #[cfg(target_os = "linux")]
         {
             std::thread::sleep(config.sleep_between_page_sizes);
-            let timing_2mib = linux::fault_2mib()?;
+            let timing_huge = linux::fault_huge_page(page_sizes.huge)?;
 
             let wallnow = OffsetDateTime::now_utc();
             println!(
-                "{wallnow} 4kiB: mmap:{:?} fault:{:?} second_write:{:?};   2MiB: mmap:{:?} fault:{:?} second_write:{:?}",
-                timing_4kib.mmap,
-                timing_4kib.fault,
-                timing_4kib.second_write,
-                timing_2mib.mmap,
-                timing_2mib.fault,
-                timing_2mib.second_write,
+                "{wallnow} {base_string}: mmap:{:?} fault:{:?} second_write:{:?};   {}: mmap:{:?} fault:{:?} second_write:{:?}",
+                timing_base.mmap,
+                timing_base.fault,
+                timing_base.second_write,
+                page_size_string(page_sizes.huge),
+                timing_huge.mmap,
+                timing_huge.fault,
+                timing_huge.second_write,
             );
         }
         #[cfg(not(target_os = "linux"))]
         {
             let wallnow = OffsetDateTime::now_utc();
             println!(
-                "{wallnow} 4kiB: mmap:{:?} fault:{:?} second_write:{:?}",
-                timing_4kib.mmap, timing_4kib.fault, timing_4kib.second_write,
+                "{wallnow} {base_string}: mmap:{:?} fault:{:?} second_write:{:?}",
+                timing_base.mmap, timing_base.fault, timing_base.second_write,
             );
         }
     }
@@ -132,13 +119,11 @@ mod linux {
     }
 
     impl MmapMadviseNoUnmap {
-        fn new(size: usize) -> Result<Self, nix::errno::Errno> {
-            const ALIGNMENT_2MIB: usize = 2 << 20;
-
+        fn new(alignment: usize, size: usize) -> Result<Self, hugepagedemo::Error> {
             // worse case alignment: mmap returns 1 byte off the alignment, we must waste alignment-1 bytes.
             // To ensure we can do this, we request size+alignment bytes.
             // This shouldn't be so bad: untouched pages won't actually be allocated.
-            let align_rounded_size = size + ALIGNMENT_2MIB;
+            let align_rounded_size = size + alignment;
             let region = MmapRegion::new(align_rounded_size)?;
 
             // Calculate the aligned block, preferring the HIGHEST aligned address,
@@ -146,17 +131,18 @@ mod linux {
             // This allows consecutive calls to mmap to be contiguous, which MIGHT
             // allow the kernel to coalesce them into huge pages? Not sure.
             let allocation_end = region.get_mut() as usize + align_rounded_size;
-            let aligned_pointer_usize =
-                align_pointer_value_down(ALIGNMENT_2MIB, allocation_end - size);
+            let aligned_pointer_usize = align_pointer_value_down(alignment, allocation_end - size);
 
             assert!(region.ptr_as_usize() <= aligned_pointer_usize);
             assert!(aligned_pointer_usize + size <= allocation_end);
 
             let aligned_pointer = NonNull::new(aligned_pointer_usize as *mut c_void).unwrap();
-            unsafe {
-                nix::sys::mman::madvise(aligned_pointer, size, MmapAdvise::MADV_HUGEPAGE)
-                    .expect("BUG: madvise must succeed");
-            }
+            unsafe { nix::sys::mman::madvise(aligned_pointer, size, MmapAdvise::MADV_HUGEPAGE) }
+                .map_err(|errno| hugepagedemo::Error::UnsupportedAdvice {
+                    advice: "madvise(MADV_HUGEPAGE)",
+                    errno,
+                    requires: "a kernel with CONFIG_TRANSPARENT_HUGEPAGE".to_string(),
+                })?;
 
             Ok(Self {
                 _region: region,
@@ -178,11 +164,10 @@ mod linux {
         pointer_value & alignment_mask
     }
 
-    pub fn fault_2mib() -> Result<FaultLatency, nix::errno::Errno> {
-        const PAGE_2MIB: usize = 2 << 20;
-
+    /// Measures faulting in a transparent huge page of `huge_page_size`.
+    pub fn fault_huge_page(huge_page_size: usize) -> Result<FaultLatency, hugepagedemo::Error> {
         let start = Instant::now();
-        let region = MmapMadviseNoUnmap::new(PAGE_2MIB)?;
+        let region = MmapMadviseNoUnmap::new(huge_page_size, huge_page_size)?;
         let mmap_end = Instant::now();
         let u64_pointer = region.as_ptr().cast::<u64>();
         unsafe {
@@ -0,0 +1,318 @@
+use crate::tlbsim::{TlbArrayConfig, TlbConfig};
+use std::arch::x86_64::{__cpuid, __cpuid_count, CpuidResult};
+
+// Decodes the data TLB geometry from CPUID. See the Intel SDM Vol. 2A "CPUID" for leaves 0x2 and
+// 0x18, and the AMD APM Vol. 3 Appendix E for leaves 0x80000005, 0x80000006 and 0x80000019.
+
+const SIZE_4K: usize = 4 << 10;
+const SIZE_2M: usize = 2 << 20;
+const SIZE_1G: usize = 1 << 30;
+
+/// The data TLB geometry reported by CPUID, and the leaf it was decoded from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuidTlb {
+    pub source: &'static str,
+    pub config: TlbConfig,
+}
+
+/// Returns the data TLB geometry of the current CPU, or None if CPUID does not describe it.
+#[must_use]
+pub fn read_cpuid_tlb() -> Option<CpuidTlb> {
+    let vendor_leaf = __cpuid(0);
+    let max_leaf = vendor_leaf.eax;
+    let mut vendor = [0u8; 12];
+    vendor[..4].copy_from_slice(&vendor_leaf.ebx.to_le_bytes());
+    vendor[4..8].copy_from_slice(&vendor_leaf.edx.to_le_bytes());
+    vendor[8..].copy_from_slice(&vendor_leaf.ecx.to_le_bytes());
+
+    match &vendor {
+        b"AuthenticAMD" | b"HygonGenuine" => {
+            let max_extended_leaf = __cpuid(0x8000_0000).eax;
+            if max_extended_leaf < 0x8000_0006 {
+                return None;
+            }
+            let l1_1g = (max_extended_leaf >= 0x8000_0019).then(|| __cpuid(0x8000_0019));
+            let config = decode_amd(__cpuid(0x8000_0005), __cpuid(0x8000_0006), l1_1g);
+            Some(CpuidTlb {
+                source: "AMD leaves 0x80000005/6/19",
+                config,
+            })
+        }
+        _ => {
+            if max_leaf >= 0x18 {
+                let max_subleaf = __cpuid_count(0x18, 0).eax;
+                let subleaves = (0..=max_subleaf)
+                    .map(|subleaf| __cpuid_count(0x18, subleaf))
+                    .collect::<Vec<_>>();
+                let config = decode_leaf_0x18(&subleaves);
+                if !config.levels.is_empty() {
+                    return Some(CpuidTlb {
+                        source: "leaf 0x18",
+                        config,
+                    });
+                }
+            }
+            if max_leaf >= 0x2 {
+                let config = decode_leaf_0x2(&__cpuid(0x2));
+                if !config.levels.is_empty() {
+                    return Some(CpuidTlb {
+                        source: "leaf 0x2",
+                        config,
+                    });
+                }
+            }
+            None
+        }
+    }
+}
+
+/// Collects (level, array) pairs into a config, with levels numbered from 1.
+fn config_from_levels(mut arrays: Vec<(usize, TlbArrayConfig)>) -> TlbConfig {
+    arrays.sort_by_key(|(level, _)| *level);
+    let mut levels: Vec<Vec<TlbArrayConfig>> = Vec::new();
+    let mut last_level = 0;
+    for (level, array) in arrays {
+        if level != last_level {
+            levels.push(Vec::new());
+            last_level = level;
+        }
+        levels.last_mut().unwrap().push(array);
+    }
+    TlbConfig { levels }
+}
+
+/// Returns an array config, or None if it has no entries. The simulator requires entries to be a
+/// multiple of ways, which is not true for some AMD TLBs: treat those as fully associative.
+fn array_config(entries: usize, ways: usize, page_sizes: Vec<usize>) -> Option<TlbArrayConfig> {
+    if entries == 0 || page_sizes.is_empty() {
+        return None;
+    }
+    let ways = if ways == 0 || !entries.is_multiple_of(ways) {
+        entries
+    } else {
+        ways
+    };
+    Some(TlbArrayConfig {
+        entries,
+        ways,
+        page_sizes,
+    })
+}
+
+/// Decodes the deterministic address translation parameters from each subleaf of leaf 0x18.
+fn decode_leaf_0x18(subleaves: &[CpuidResult]) -> TlbConfig {
+    const TYPE_DATA: u32 = 1;
+    const TYPE_UNIFIED: u32 = 3;
+    const TYPE_LOAD_ONLY: u32 = 4;
+
+    let mut arrays = Vec::new();
+    for subleaf in subleaves {
+        let tlb_type = subleaf.edx & 0x1f;
+        if !matches!(tlb_type, TYPE_DATA | TYPE_UNIFIED | TYPE_LOAD_ONLY) {
+            continue;
+        }
+        let level = ((subleaf.edx >> 5) & 0x7) as usize;
+        let fully_associative = subleaf.edx & (1 << 8) != 0;
+        let ways = (subleaf.ebx >> 16) as usize;
+        let sets = subleaf.ecx as usize;
+
+        let mut page_sizes = Vec::new();
+        // bit 2 is 4M pages, which are only used without PAE: ignore it
+        for (bit, size) in [(0, SIZE_4K), (1, SIZE_2M), (3, SIZE_1G)] {
+            if subleaf.ebx & (1 << bit) != 0 {
+                page_sizes.push(size);
+            }
+        }
+        let entries = ways * sets;
+        let ways = if fully_associative { entries } else { ways };
+        if let Some(array) = array_config(entries, ways, page_sizes) {
+            arrays.push((level, array));
+        }
+    }
+    config_from_levels(arrays)
+}
+
+/// Decodes the data TLB descriptors in leaf 0x2. Descriptors for 4M pages are reported as 2M,
+/// since in 64-bit mode these entries hold 2M pages.
+fn decode_leaf_0x2(leaf: &CpuidResult) -> TlbConfig {
+    // (descriptor, level, entries, ways, page sizes); ways == entries is fully associative
+    const DESCRIPTORS: &[(u8, usize, usize, usize, &[usize])] = &[
+        (0x03, 1, 64, 4, &[SIZE_4K]),
+        (0x04, 1, 8, 4, &[SIZE_2M]),
+        (0x05, 2, 32, 4, &[SIZE_2M]),
+        (0x56, 1, 16, 4, &[SIZE_2M]),
+        (0x57, 1, 16, 4, &[SIZE_4K]),
+        (0x59, 1, 16, 16, &[SIZE_4K]),
+        (0x5A, 1, 32, 4, &[SIZE_2M]),
+        (0x5B, 1, 64, 64, &[SIZE_4K, SIZE_2M]),
+        (0x5C, 1, 128, 128, &[SIZE_4K, SIZE_2M]),
+        (0x5D, 1, 256, 256, &[SIZE_4K, SIZE_2M]),
+        (0x63, 1, 32, 4, &[SIZE_2M]),
+        (0x64, 1, 512, 4, &[SIZE_4K]),
+        (0xA0, 1, 32, 32, &[SIZE_4K]),
+        (0xB3, 1, 128, 4, &[SIZE_4K]),
+        (0xB4, 2, 256, 4, &[SIZE_4K]),
+        (0xBA, 2, 64, 4, &[SIZE_4K]),
+        (0xC0, 1, 8, 4, &[SIZE_4K, SIZE_2M]),
+        (0xC1, 2, 1024, 8, &[SIZE_4K, SIZE_2M]),
+        (0xC2, 1, 16, 4, &[SIZE_4K, SIZE_2M]),
+        (0xC3, 2, 1536, 6, &[SIZE_4K, SIZE_2M]),
+        (0xC4, 1, 32, 4, &[SIZE_2M]),
+        (0xCA, 2, 512, 4, &[SIZE_4K]),
+    ];
+    // descriptors that also describe a separate 1G array
+    const DESCRIPTORS_1G: &[(u8, usize, usize, usize)] = &[(0x63, 1, 4, 4), (0xC3, 2, 16, 4)];
+
+    let mut arrays = Vec::new();
+    for (register_index, register) in [leaf.eax, leaf.ebx, leaf.ecx, leaf.edx]
+        .into_iter()
+        .enumerate()
+    {
+        // bit 31 set means the register does not contain valid descriptors
+        if register & (1 << 31) != 0 {
+            continue;
+        }
+        for (byte_index, descriptor) in register.to_le_bytes().into_iter().enumerate() {
+            // the low byte of eax is always 0x01, and is not a descriptor
+            if register_index == 0 && byte_index == 0 {
+                continue;
+            }
+            for &(d, level, entries, ways, sizes) in DESCRIPTORS {
+                if d == descriptor {
+                    arrays.extend(
+                        array_config(entries, ways, sizes.to_vec()).map(|array| (level, array)),
+                    );
+                }
+            }
+            for &(d, level, entries, ways) in DESCRIPTORS_1G {
+                if d == descriptor {
+                    arrays.extend(
+                        array_config(entries, ways, vec![SIZE_1G]).map(|array| (level, array)),
+                    );
+                }
+            }
+        }
+    }
+    config_from_levels(arrays)
+}
+
+/// Decodes the 4-bit L2 associativity encoding used by AMD leaves 0x80000006 and 0x80000019.
+/// Returns Some(0) for fully associative, and None for disabled or reserved values.
+const fn amd_l2_ways(encoded: u32) -> Option<usize> {
+    match encoded {
+        1 => Some(1),
+        2 => Some(2),
+        3 => Some(3),
+        4 => Some(4),
+        5 => Some(6),
+        6 => Some(8),
+        8 => Some(16),
+        0xA => Some(32),
+        0xB => Some(48),
+        0xC => Some(64),
+        0xD => Some(96),
+        0xE => Some(128),
+        0xF => Some(0),
+        _ => None,
+    }
+}
+
+/// Decodes the AMD L1 TLB (0x80000005), L2 TLB (0x80000006), and 1G TLB (0x80000019) leaves.
+fn decode_amd(l1: CpuidResult, l2: CpuidResult, tlb_1g: Option<CpuidResult>) -> TlbConfig {
+    let mut arrays = Vec::new();
+
+    // L1: data TLB in bits 31:16: 8-bit associativity (0xff is fully) and 8-bit entries
+    for (register, size) in [(l1.ebx, SIZE_4K), (l1.eax, SIZE_2M)] {
+        let ways = (register >> 24) as usize;
+        let entries = ((register >> 16) & 0xff) as usize;
+        let ways = if ways == 0xff { entries } else { ways };
+        arrays.extend(array_config(entries, ways, vec![size]).map(|array| (1, array)));
+    }
+
+    // L2 and 1G: data TLB in bits 31:16: 4-bit encoded associativity and 12-bit entries
+    let mut l2_registers = vec![(2, l2.ebx, SIZE_4K), (2, l2.eax, SIZE_2M)];
+    if let Some(tlb_1g) = tlb_1g {
+        l2_registers.push((1, tlb_1g.eax, SIZE_1G));
+        l2_registers.push((2, tlb_1g.ebx, SIZE_1G));
+    }
+    for (level, register, size) in l2_registers {
+        let entries = ((register >> 16) & 0xfff) as usize;
+        let Some(ways) = amd_l2_ways(register >> 28) else {
+            continue;
+        };
+        arrays.extend(array_config(entries, ways, vec![size]).map(|array| (level, array)));
+    }
+    config_from_levels(arrays)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const fn cpuid(eax: u32, ebx: u32, ecx: u32, edx: u32) -> CpuidResult {
+        CpuidResult { eax, ebx, ecx, edx }
+    }
+
+    #[test]
+    fn test_decode_leaf_0x18() {
+        // Intel Alder Lake P-core style subleaves
+        let subleaves = [
+            // subleaf 0: max subleaf=4; instruction TLB 4K+2M, 8-way 32 sets
+            cpuid(4, 0x0008_0003, 32, 0x0000_0022),
+            // load-only L1 4K, 16-way 4 sets
+            cpuid(0, 0x0010_0001, 4, 0x0000_0024),
+            // load-only L1 2M, 8-way 4 sets
+            cpuid(0, 0x0008_0002, 4, 0x0000_0024),
+            // load-only L1 1G, fully associative 8 ways
+            cpuid(0, 0x0008_0008, 1, 0x0000_0124),
+            // unified L2 4K+2M, 8-way 256 sets
+            cpuid(0, 0x0008_0003, 256, 0x0000_0043),
+        ];
+        let config = decode_leaf_0x18(&subleaves);
+        assert_eq!("64x16:4K,32x8:2M,8x8:1G/2048x8:4K+2M", config.to_string());
+    }
+
+    #[test]
+    fn test_decode_leaf_0x2() {
+        // Skylake: 0x63 (DTLB 2M 32 + 1G 4), 0x03 (DTLB 4K 64), 0xC3 (STLB 1536 + 1G 16),
+        // 0x76 (instruction TLB: ignored), 0xFF (use leaf 4)
+        let leaf = cpuid(
+            0x0076_0301 | 0x6300_0000,
+            0x0000_00FF,
+            0x0000_0000,
+            0x00C3_0000,
+        );
+        let config = decode_leaf_0x2(&leaf);
+        assert_eq!(
+            "64x4:4K,32x4:2M,4x4:1G/1536x6:4K+2M,16x4:1G",
+            config.to_string()
+        );
+
+        // invalid register
+        let config = decode_leaf_0x2(&cpuid(0x8000_0301, 0, 0, 0));
+        assert!(config.levels.is_empty());
+    }
+
+    #[test]
+    fn test_decode_amd() {
+        // Zen 3: L1 dTLB 64 entries fully associative for 4K and 2M
+        let l1 = cpuid(0xff40_ff40, 0xff40_ff40, 0, 0);
+        // L2 dTLB 2048 entries 8-way for 4K and 2M (0x6 is 8-way)
+        let l2 = cpuid(0x6800_6400, 0x6800_6400, 0, 0);
+        // L1 1G 64 entries fully associative; L2 1G 2048 entries 8-way
+        let tlb_1g = cpuid(0xf040_f040, 0x6800_6400, 0, 0);
+        let config = decode_amd(l1, l2, Some(tlb_1g));
+        assert_eq!(
+            "64x64:4K,64x64:2M,64x64:1G/2048x8:4K,2048x8:2M,2048x8:1G",
+            config.to_string()
+        );
+    }
+
+    #[test]
+    fn test_read_cpuid_tlb() {
+        // only tests that it does not crash: not all CPUs or VMs report TLBs
+        if let Some(tlb) = read_cpuid_tlb() {
+            assert!(!tlb.config.levels.is_empty());
+        }
+    }
+}
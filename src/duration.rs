@@ -0,0 +1,22 @@
+use std::time::Duration;
+
+/// Parses a duration using Go's formats like `1m30s`, with the signature required by clap.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let nanos = go_parse_duration::parse_duration(s).map_err(|err| format!("{err:?}"))?;
+    let nanos = u64::try_from(nanos).map_err(|_| format!("duration {s:?} must not be negative"))?;
+    Ok(Duration::from_nanos(nanos))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(Ok(Duration::from_millis(100)), parse_duration("100ms"));
+        assert_eq!(Ok(Duration::from_secs(90)), parse_duration("1m30s"));
+        assert_eq!(Ok(Duration::from_hours(1)), parse_duration("1h"));
+        assert!(parse_duration("-1s").is_err());
+        assert!(parse_duration("1 second").is_err());
+    }
+}
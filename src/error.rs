@@ -0,0 +1,154 @@
+use nix::errno::Errno;
+use std::borrow::Cow;
+
+/// Errors returned by this crate. The message for each error ends with how to fix it, from
+/// [`Error::remediation`].
+#[derive(Debug)]
+pub enum Error {
+    /// The page containing address has never been written, so it has no page frame.
+    NotPresent {
+        address: usize,
+    },
+    /// The page containing address was swapped out (pagemap bit 62).
+    Swapped {
+        address: usize,
+    },
+    /// Reading path requires root, usually `CAP_SYS_ADMIN`.
+    PermissionDenied {
+        path: String,
+    },
+    /// The kernel rejected a madvise or prctl advice, because it is too old or the feature is
+    /// compiled out. requires describes what is needed.
+    UnsupportedAdvice {
+        advice: &'static str,
+        errno: Errno,
+        requires: String,
+    },
+    /// Could not parse what, which is a kernel interface like `/proc/self/smaps`, or an argument.
+    Parse {
+        what: String,
+        message: String,
+    },
+    /// A system call like mmap failed.
+    Os {
+        call: &'static str,
+        errno: Errno,
+    },
+    Io(std::io::Error),
+}
+
+impl Error {
+    #[must_use]
+    pub fn parse(what: impl Into<String>, message: impl Into<String>) -> Self {
+        Self::Parse {
+            what: what.into(),
+            message: message.into(),
+        }
+    }
+
+    /// Returns the error for failing to open or read path. Permission errors are returned as
+    /// [`Error::PermissionDenied`], since the files used by this crate are only restricted for
+    /// non-root users.
+    #[must_use]
+    pub fn from_io_path(err: &std::io::Error, path: &str) -> Self {
+        if err.kind() == std::io::ErrorKind::PermissionDenied {
+            return Self::PermissionDenied {
+                path: path.to_string(),
+            };
+        }
+        Self::Io(std::io::Error::new(err.kind(), format!("{path}: {err}")))
+    }
+
+    /// Returns a description of how to fix this error.
+    #[must_use]
+    pub fn remediation(&self) -> Cow<'static, str> {
+        match self {
+            Self::NotPresent { .. } => "write to the memory before checking its pages".into(),
+            Self::Swapped { .. } => {
+                "lock the memory with mlock, or disable swap with swapoff -a".into()
+            }
+            Self::PermissionDenied { .. } => {
+                "run as root outside a container (requires CAP_SYS_ADMIN)".into()
+            }
+            Self::UnsupportedAdvice { requires, .. } => format!("requires {requires}").into(),
+            Self::Parse { .. } => {
+                "check the format, or report a bug if it was read from /proc or /sys".into()
+            }
+            Self::Os {
+                errno: Errno::ENOMEM,
+                ..
+            } => "not enough memory; hugetlb pages must be reserved in /sys/kernel/mm/hugepages"
+                .into(),
+            Self::Os {
+                errno: Errno::EPERM | Errno::EACCES,
+                ..
+            } => "run as root".into(),
+            Self::Os { .. } => "check the arguments and the system call's man page".into(),
+            Self::Io(_) => "check that the file exists and is readable".into(),
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotPresent { address } => write!(f, "page at 0x{address:x} is not present"),
+            Self::Swapped { address } => write!(f, "page at 0x{address:x} is swapped out"),
+            Self::PermissionDenied { path } => write!(f, "permission denied reading {path}"),
+            Self::UnsupportedAdvice { advice, errno, .. } => {
+                write!(f, "{advice} failed with {errno}")
+            }
+            Self::Parse { what, message } => write!(f, "failed to parse {what}: {message}"),
+            Self::Os { call, errno } => write!(f, "{call} failed: {errno}"),
+            Self::Io(err) => write!(f, "{err}"),
+        }?;
+        write!(f, ": {}", self.remediation())
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::UnsupportedAdvice { errno, .. } | Self::Os { errno, .. } => Some(errno),
+            Self::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let err = Error::NotPresent { address: 0x1000 };
+        assert_eq!(
+            "page at 0x1000 is not present: write to the memory before checking its pages",
+            err.to_string()
+        );
+
+        let err = Error::UnsupportedAdvice {
+            advice: "madvise(MADV_COLLAPSE)",
+            errno: Errno::EINVAL,
+            requires: "Linux 6.1 or later".to_string(),
+        };
+        assert_eq!(
+            "madvise(MADV_COLLAPSE) failed with EINVAL: Invalid argument: requires Linux 6.1 or later",
+            err.to_string()
+        );
+
+        let denied = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        let err = Error::from_io_path(&denied, "/proc/kpageflags");
+        assert!(matches!(err, Error::PermissionDenied { .. }), "err={err}");
+        let missing = std::io::Error::from(std::io::ErrorKind::NotFound);
+        let err = Error::from_io_path(&missing, "/proc/kpagecgroup");
+        assert!(err.to_string().contains("/proc/kpagecgroup"), "err={err}");
+    }
+}
@@ -0,0 +1,129 @@
+use crate::Error;
+use std::fs::File;
+use std::path::PathBuf;
+
+/// The directory that the kernel interfaces in `/proc` and `/sys` are read from.
+///
+/// This is `/` by default. Tests use a directory containing copies of these files captured from
+/// other machines, so the parsers can be tested without depending on the host's configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KernelFs {
+    root: PathBuf,
+}
+
+impl KernelFs {
+    #[must_use]
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// Returns the location of path, an absolute path like `/proc/self/smaps`, under the root.
+    #[must_use]
+    pub fn path(&self, path: &str) -> PathBuf {
+        self.root.join(path.trim_start_matches('/'))
+    }
+
+    /// Returns the error for failing to read path. See [`Error::from_io_path`].
+    #[must_use]
+    pub fn io_error(&self, err: &std::io::Error, path: &str) -> Error {
+        Error::from_io_path(err, &self.path(path).to_string_lossy())
+    }
+
+    pub fn open(&self, path: &str) -> Result<File, Error> {
+        File::open(self.path(path)).map_err(|err| self.io_error(&err, path))
+    }
+
+    pub fn read(&self, path: &str) -> Result<Vec<u8>, Error> {
+        std::fs::read(self.path(path)).map_err(|err| self.io_error(&err, path))
+    }
+
+    /// Returns the names of the entries in the directory at path, sorted.
+    pub fn read_dir_names(&self, path: &str) -> Result<Vec<String>, Error> {
+        let read_error = |err| self.io_error(&err, path);
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(self.path(path)).map_err(read_error)? {
+            let entry = entry.map_err(read_error)?;
+            names.push(entry.file_name().to_string_lossy().into_owned());
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Reads a file containing a single integer terminated by a newline, as used by sysfs.
+    pub fn read_usize(&self, path: &str) -> Result<usize, Error> {
+        let value = self.read(path)?;
+        // always terminated by \n
+        let value = value.strip_suffix(b"\n").unwrap_or(&value);
+        String::from_utf8_lossy(value)
+            .parse::<usize>()
+            .map_err(|err| Error::parse(self.path(path).display().to_string(), err.to_string()))
+    }
+}
+
+#[cfg(test)]
+impl KernelFs {
+    /// Returns the fixture directory in testdata described by name.
+    pub(crate) fn fixture(name: &str) -> Self {
+        Self::new(
+            std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("testdata")
+                .join(name),
+        )
+    }
+
+    /// Returns a directory without any kernel files, like a kernel built without
+    /// `CONFIG_TRANSPARENT_HUGEPAGE` or `CONFIG_NUMA`. No fixture was captured from one.
+    pub(crate) fn missing_fixture() -> Self {
+        Self::fixture("missing")
+    }
+}
+
+impl Default for KernelFs {
+    fn default() -> Self {
+        Self::new("/")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn test_kernel_fs() {
+        assert_eq!(
+            Path::new("/proc/self/smaps"),
+            KernelFs::default().path("/proc/self/smaps")
+        );
+        assert_eq!(
+            Path::new("/tmp/x86_64/proc/self/smaps"),
+            KernelFs::new("/tmp/x86_64").path("/proc/self/smaps")
+        );
+
+        let fs = KernelFs::fixture("synthetic-arm64-64k");
+        assert_eq!(
+            512 << 20,
+            fs.read_usize("/sys/kernel/mm/transparent_hugepage/hpage_pmd_size")
+                .unwrap()
+        );
+        assert_eq!(
+            vec![
+                "hugepages-16777216kB",
+                "hugepages-2048kB",
+                "hugepages-524288kB"
+            ],
+            fs.read_dir_names("/sys/kernel/mm/hugepages").unwrap()
+        );
+
+        // errors report the path under the root, so it is clear a fixture was used
+        let err = KernelFs::fixture("x86_64")
+            .read_usize("/proc/kpagecount")
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("testdata/x86_64/proc/kpagecount"),
+            "err={err}"
+        );
+        let err = fs.read_usize("/proc/self/status").unwrap_err();
+        assert!(matches!(err, Error::Parse { .. }), "err={err}");
+    }
+}
@@ -0,0 +1,199 @@
+use crate::{Error, KernelFs};
+use std::time::Duration;
+
+// See: https://www.kernel.org/doc/html/latest/admin-guide/mm/transhuge.html#khugepaged-controls
+const KHUGEPAGED_PATH: &str = "/sys/kernel/mm/transparent_hugepage/khugepaged";
+
+/// The counters and scan rate of khugepaged, the kernel thread that collapses base pages into
+/// transparent huge pages in the background.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KhugepagedStats {
+    /// The number of completed scans of all memory that can use huge pages, for every process.
+    pub full_scans: usize,
+    /// The number of huge pages khugepaged has collapsed, for every process.
+    pub pages_collapsed: usize,
+    /// The number of base pages scanned each time khugepaged wakes up.
+    pub pages_to_scan: usize,
+    /// The time khugepaged sleeps between scans.
+    pub scan_sleep: Duration,
+    /// The time khugepaged sleeps after it fails to allocate a huge page, which stops it from
+    /// collapsing anything while memory is fragmented.
+    pub alloc_sleep: Duration,
+}
+
+impl KhugepagedStats {
+    /// Returns the current values. Returns a `NotFound` error if the kernel does not support
+    /// transparent huge pages.
+    pub fn read(fs: &KernelFs) -> Result<Self, Error> {
+        let read = |name: &str| fs.read_usize(&format!("{KHUGEPAGED_PATH}/{name}"));
+        Ok(Self {
+            full_scans: read("full_scans")?,
+            pages_collapsed: read("pages_collapsed")?,
+            pages_to_scan: read("pages_to_scan")?,
+            scan_sleep: Duration::from_millis(read("scan_sleep_millisecs")? as u64),
+            alloc_sleep: Duration::from_millis(read("alloc_sleep_millisecs")? as u64),
+        })
+    }
+
+    /// Returns the least time khugepaged needs to scan `size_bytes` of memory made of base pages
+    /// of `base_page_size`. It scans every process, so a full scan of the system takes longer.
+    #[must_use]
+    pub fn scan_duration(&self, size_bytes: usize, base_page_size: usize) -> Duration {
+        let wakeups = (size_bytes / base_page_size).div_ceil(self.pages_to_scan.max(1));
+        self.scan_sleep
+            .saturating_mul(u32::try_from(wakeups).unwrap_or(u32::MAX))
+    }
+}
+
+/// One sample of a region while khugepaged collapses it into huge pages.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CollapseSample {
+    /// The time since the region was advised to use huge pages.
+    pub elapsed: Duration,
+    /// The bytes of the region in huge pages.
+    pub huge_bytes: usize,
+    pub khugepaged: KhugepagedStats,
+    /// The average time for one random access to the region, in nanoseconds.
+    pub access_ns: f64,
+}
+
+/// How the huge page coverage of a region of `size_bytes` changes over time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CollapseCurve {
+    pub size_bytes: usize,
+    pub samples: Vec<CollapseSample>,
+}
+
+impl CollapseCurve {
+    #[must_use]
+    pub const fn new(size_bytes: usize) -> Self {
+        Self {
+            size_bytes,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, sample: CollapseSample) {
+        self.samples.push(sample);
+    }
+
+    /// Returns true if the coverage will not change: the entire region is huge pages, or
+    /// khugepaged has started and finished a full scan since the coverage last changed. The scan
+    /// that was in progress when it changed may have already passed the region, so one completed
+    /// scan is not enough.
+    #[must_use]
+    pub fn is_stable(&self) -> bool {
+        let Some(last) = self.samples.last() else {
+            return false;
+        };
+        if last.huge_bytes >= self.size_bytes {
+            return true;
+        }
+        let changed = self
+            .samples
+            .iter()
+            .rev()
+            .take_while(|sample| sample.huge_bytes == last.huge_bytes)
+            .last()
+            .expect("BUG: the last sample must match itself");
+        last.khugepaged.full_scans >= changed.khugepaged.full_scans + 2
+    }
+
+    /// Returns the elapsed time of the first sample with at least percent of the region in huge
+    /// pages, or None if no sample reached it.
+    #[must_use]
+    pub fn time_to_percent(&self, percent: usize) -> Option<Duration> {
+        self.samples
+            .iter()
+            .find(|sample| sample.huge_bytes * 100 >= self.size_bytes * percent)
+            .map(|sample| sample.elapsed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_read_khugepaged_stats() {
+        let stats = KhugepagedStats::read(&KernelFs::fixture("x86_64")).unwrap();
+        assert_eq!(
+            KhugepagedStats {
+                full_scans: 25,
+                pages_collapsed: 4081,
+                pages_to_scan: 4096,
+                scan_sleep: Duration::from_secs(10),
+                alloc_sleep: Duration::from_mins(1),
+            },
+            stats
+        );
+        // 4 GiB is 1Mi base pages: 256 wakeups
+        assert_eq!(
+            Duration::from_secs(2560),
+            stats.scan_duration(4 << 30, 4 << 10)
+        );
+
+        // 64 KiB base pages: pages_to_scan is 8 huge pages of 8192 base pages each
+        let stats = KhugepagedStats::read(&KernelFs::fixture("synthetic-arm64-64k")).unwrap();
+        assert_eq!(65536, stats.pages_to_scan);
+        assert_eq!(
+            Duration::from_secs(10),
+            stats.scan_duration(4 << 30, 64 << 10)
+        );
+
+        // khugepaged still exists when transparent huge pages are never used
+        let stats = KhugepagedStats::read(&KernelFs::fixture("thp-never")).unwrap();
+        assert_eq!(4096, stats.pages_to_scan);
+
+        let err = KhugepagedStats::read(&KernelFs::missing_fixture()).unwrap_err();
+        assert!(
+            matches!(&err, Error::Io(err) if err.kind() == std::io::ErrorKind::NotFound),
+            "err={err}"
+        );
+    }
+
+    #[test]
+    fn test_collapse_curve() {
+        const MIB: usize = 1 << 20;
+        let sample = |elapsed_secs, huge_mib, full_scans| CollapseSample {
+            elapsed: Duration::from_secs(elapsed_secs),
+            huge_bytes: huge_mib * MIB,
+            khugepaged: KhugepagedStats {
+                full_scans,
+                pages_collapsed: 0,
+                pages_to_scan: 4096,
+                scan_sleep: Duration::from_secs(10),
+                alloc_sleep: Duration::from_mins(1),
+            },
+            access_ns: 50.0,
+        };
+
+        let mut curve = CollapseCurve::new(100 * MIB);
+        assert!(!curve.is_stable());
+        assert_eq!(None, curve.time_to_percent(0));
+        curve.push(sample(0, 0, 5));
+        assert!(!curve.is_stable());
+        // not changing, but only one full scan has finished since the first sample
+        curve.push(sample(10, 0, 6));
+        assert!(!curve.is_stable());
+        curve.push(sample(20, 30, 6));
+        curve.push(sample(30, 60, 7));
+        curve.push(sample(40, 60, 8));
+        assert!(!curve.is_stable());
+        curve.push(sample(50, 60, 9));
+        assert!(curve.is_stable());
+
+        assert_eq!(Some(Duration::ZERO), curve.time_to_percent(0));
+        assert_eq!(Some(Duration::from_secs(20)), curve.time_to_percent(25));
+        assert_eq!(Some(Duration::from_secs(30)), curve.time_to_percent(50));
+        assert_eq!(Some(Duration::from_secs(30)), curve.time_to_percent(60));
+        assert_eq!(None, curve.time_to_percent(90));
+
+        // stable as soon as the entire region is huge pages
+        let mut curve = CollapseCurve::new(100 * MIB);
+        curve.push(sample(0, 0, 5));
+        curve.push(sample(5, 100, 5));
+        assert!(curve.is_stable());
+        assert_eq!(Some(Duration::from_secs(5)), curve.time_to_percent(100));
+    }
+}
@@ -0,0 +1,167 @@
+use crate::{Error, KernelFs};
+use std::io::{Read, Seek, SeekFrom};
+
+bitflags::bitflags! {
+    /// The flags for a page frame from `/proc/kpageflags`. See
+    /// <https://www.kernel.org/doc/html/latest/admin-guide/mm/pagemap.html> and
+    /// <https://github.com/torvalds/linux/blob/master/include/uapi/linux/kernel-page-flags.h>.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct KPageFlags: u64 {
+        const LOCKED = 1 << 0;
+        const ERROR = 1 << 1;
+        const REFERENCED = 1 << 2;
+        const UPTODATE = 1 << 3;
+        const DIRTY = 1 << 4;
+        const LRU = 1 << 5;
+        const ACTIVE = 1 << 6;
+        const SLAB = 1 << 7;
+        const WRITEBACK = 1 << 8;
+        const RECLAIM = 1 << 9;
+        /// A free page in the buddy allocator.
+        const BUDDY = 1 << 10;
+        /// Mapped into at least one process.
+        const MMAP = 1 << 11;
+        const ANON = 1 << 12;
+        const SWAPCACHE = 1 << 13;
+        const SWAPBACKED = 1 << 14;
+        /// The first base page of a compound page, like a huge page.
+        const COMPOUND_HEAD = 1 << 15;
+        /// Any base page of a compound page except the first.
+        const COMPOUND_TAIL = 1 << 16;
+        /// Part of a hugetlb page.
+        const HUGE = 1 << 17;
+        const UNEVICTABLE = 1 << 18;
+        const HWPOISON = 1 << 19;
+        /// No page frame exists at this PFN.
+        const NOPAGE = 1 << 20;
+        const KSM = 1 << 21;
+        /// Part of a transparent huge page.
+        const THP = 1 << 22;
+        /// Logically offline; called BALLOON before Linux 5.0.
+        const OFFLINE = 1 << 23;
+        const ZERO_PAGE = 1 << 24;
+        /// Not accessed since it was marked idle in `/sys/kernel/mm/page_idle/bitmap`.
+        const IDLE = 1 << 25;
+        const PGTABLE = 1 << 26;
+
+        // the remaining flags are from the kernel's internal include/linux/kernel-page-flags.h
+        const RESERVED = 1 << 32;
+        const MLOCKED = 1 << 33;
+        /// Called MAPPEDTODISK before Linux 6.10.
+        const OWNER_2 = 1 << 34;
+        const PRIVATE = 1 << 35;
+        const PRIVATE_2 = 1 << 36;
+        const OWNER_PRIVATE = 1 << 37;
+        const ARCH = 1 << 38;
+        const UNCACHED = 1 << 39;
+        const SOFTDIRTY = 1 << 40;
+        const ARCH_2 = 1 << 41;
+    }
+}
+
+impl std::fmt::Display for KPageFlags {
+    /// Formats the flag names separated by '|', like "`COMPOUND_HEAD|THP`", or "none".
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return write!(f, "none");
+        }
+        for (i, (name, _)) in self.iter_names().enumerate() {
+            if i > 0 {
+                write!(f, "|")?;
+            }
+            write!(f, "{name}")?;
+        }
+        let unknown = self.bits() & !Self::all().bits();
+        if unknown != 0 {
+            write!(f, "|0x{unknown:x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the flags for count page frames starting at pfn, from `/proc/kpageflags`. Requires
+/// root (`CAP_SYS_ADMIN`).
+pub fn read_kpageflags(fs: &KernelFs, pfn: u64, count: usize) -> Result<Vec<KPageFlags>, Error> {
+    let entries = read_u64_file_entries(fs, "/proc/kpageflags", pfn, count)?;
+    Ok(entries
+        .into_iter()
+        .map(KPageFlags::from_bits_retain)
+        .collect())
+}
+
+/// Returns the number of times each of count page frames starting at pfn is mapped, from
+/// `/proc/kpagecount`. A count greater than 1 means the page is shared. Requires root.
+pub fn read_kpagecount(fs: &KernelFs, pfn: u64, count: usize) -> Result<Vec<u64>, Error> {
+    read_u64_file_entries(fs, "/proc/kpagecount", pfn, count)
+}
+
+/// Returns the inode number of the memory cgroup that each of count page frames starting at pfn
+/// is charged to, from `/proc/kpagecgroup`. Requires root, and a kernel with `CONFIG_MEMCG`.
+pub fn read_kpagecgroup(fs: &KernelFs, pfn: u64, count: usize) -> Result<Vec<u64>, Error> {
+    read_u64_file_entries(fs, "/proc/kpagecgroup", pfn, count)
+}
+
+/// Reads count entries starting at entry index from the file at path with [`read_u64_entries`].
+fn read_u64_file_entries(
+    fs: &KernelFs,
+    path: &str,
+    index: u64,
+    count: usize,
+) -> Result<Vec<u64>, Error> {
+    let mut f = fs.open(path)?;
+    read_u64_entries(&mut f, index, count).map_err(|err| fs.io_error(&err, path))
+}
+
+/// Reads count little endian u64 entries starting at entry index, the format of the files in
+/// `/proc` that are indexed by page frame number.
+fn read_u64_entries<R: Read + Seek>(
+    r: &mut R,
+    index: u64,
+    count: usize,
+) -> Result<Vec<u64>, std::io::Error> {
+    r.seek(SeekFrom::Start(index * 8))?;
+    let mut bytes = vec![0u8; count * 8];
+    r.read_exact(&mut bytes)?;
+    Ok(bytes
+        .chunks_exact(8)
+        .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_kpageflags_display() {
+        let flags = KPageFlags::from_bits_retain((1 << 22) | (1 << 15) | (1 << 12) | (1 << 50));
+        assert!(flags.contains(KPageFlags::THP | KPageFlags::COMPOUND_HEAD));
+        assert!(!flags.contains(KPageFlags::HUGE));
+        assert_eq!("ANON|COMPOUND_HEAD|THP|0x4000000000000", flags.to_string());
+        assert_eq!("none", KPageFlags::empty().to_string());
+    }
+
+    #[test]
+    fn test_read_u64_entries() {
+        let bytes = [1u64, 2, 3, 4]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect::<Vec<_>>();
+        let mut cursor = std::io::Cursor::new(bytes);
+        assert_eq!(vec![2, 3], read_u64_entries(&mut cursor, 1, 2).unwrap());
+        assert!(read_u64_entries(&mut cursor, 3, 2).is_err());
+    }
+
+    #[test]
+    fn test_read_kpageflags() {
+        // the first page frames on x86_64 are reserved for the BIOS
+        let fs = KernelFs::fixture("x86_64");
+        let flags = read_kpageflags(&fs, 6, 2).unwrap();
+        assert_eq!(vec![KPageFlags::RESERVED; 2], flags);
+        assert!(read_kpageflags(&fs, 7, 2).is_err());
+
+        let err = read_kpagecount(&fs, 0, 1).unwrap_err();
+        assert!(matches!(&err, Error::Io(io_err) if io_err.kind() == std::io::ErrorKind::NotFound));
+        assert!(err.to_string().contains("proc/kpagecount"), "err={err}");
+    }
+}
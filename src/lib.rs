@@ -1,3 +1,34 @@
+mod accesstrace;
+pub use accesstrace::AccessTraceReader;
+pub use accesstrace::AccessTraceWriter;
+#[cfg(target_arch = "x86_64")]
+mod cpuidtlb;
+#[cfg(target_arch = "x86_64")]
+pub use cpuidtlb::{CpuidTlb, read_cpuid_tlb};
+mod duration;
+pub use duration::parse_duration;
+mod error;
+pub use error::Error;
+mod kernelfs;
+pub use kernelfs::KernelFs;
+mod khugepaged;
+pub use khugepaged::{CollapseCurve, CollapseSample, KhugepagedStats};
+mod kpageflags;
+pub use kpageflags::{KPageFlags, read_kpagecgroup, read_kpagecount, read_kpageflags};
 mod mmaputils;
 pub use mmaputils::MmapOwner;
 pub use mmaputils::MmapRegion;
+mod numa;
+pub use numa::{NumaMapsMapping, NumaPolicy, is_numa_unsupported, parse_numa_maps, read_numa_maps};
+#[cfg(target_os = "linux")]
+pub use numa::{mbind, query_page_nodes};
+mod pfnruns;
+pub use pfnruns::PfnRuns;
+mod procstatus;
+pub use procstatus::ProcessMemory;
+mod smaps;
+pub use smaps::{SmapsMapping, parse_smaps, read_smaps, sum_smaps_field};
+mod pagesize;
+pub use pagesize::{PageSizes, page_size_string, parse_page_size, sysconf_page_size};
+mod tlbsim;
+pub use tlbsim::{TlbArrayConfig, TlbConfig, TlbSimulator, TlbStats};
@@ -1,43 +1,369 @@
 use crate::anyos_hugepages;
-use nix::sys::mman::MmapAdvise;
-use std::error::Error;
+use crate::anyos_hugepages::{
+    HugepageAdvice, HugepageSetting, PageSizeBackend, ShmemHugepageSetting,
+};
+use hugepagedemo::{
+    Error, KPageFlags, KernelFs, PageSizes, PfnRuns, SmapsMapping, page_size_string,
+};
+use nix::sched::{CpuSet, sched_setaffinity};
+use nix::sys::memfd::MFdFlags;
+use nix::sys::mman::{MapFlags, MmapAdvise};
+use nix::unistd::Pid;
 use std::ffi::c_void;
-use std::fs::File;
 use std::io::Read;
 use std::io::Seek;
 use std::io::SeekFrom;
+use std::os::fd::OwnedFd;
 use std::ptr::NonNull;
 
 // See: https://www.kernel.org/doc/Documentation/vm/transhuge.txt
 const HUGEPAGE_ENABLED_PATH: &str = "/sys/kernel/mm/transparent_hugepage/enabled";
 
-pub fn print_hugepage_setting_on_linux() -> Result<(), Box<dyn Error>> {
-    let mut f = File::open(HUGEPAGE_ENABLED_PATH)?;
-    let mut v = Vec::new();
-    f.read_to_end(&mut v)?;
+pub fn read_hugepage_setting(fs: &KernelFs) -> Result<HugepageSetting, Error> {
+    anyos_hugepages::parse_hugepage_enabled(&fs.read(HUGEPAGE_ENABLED_PATH)?)
+}
 
-    let hugepage_setting = anyos_hugepages::parse_hugepage_enabled(&v)?;
+pub fn print_hugepage_setting_on_linux(fs: &KernelFs) -> Result<(), Error> {
+    let hugepage_setting = read_hugepage_setting(fs)?;
     println!("transparent_hugepage setting: {hugepage_setting}");
 
     Ok(())
 }
 
-pub fn madvise_hugepages_on_linux(slice: &mut [u64]) {
-    const HUGEPAGE_FLAGS: MmapAdvise = MmapAdvise::MADV_HUGEPAGE;
+const SHMEM_ENABLED_PATH: &str = "/sys/kernel/mm/transparent_hugepage/shmem_enabled";
+
+fn read_shmem_hugepage_setting(fs: &KernelFs) -> Result<ShmemHugepageSetting, Error> {
+    anyos_hugepages::parse_shmem_enabled(&fs.read(SHMEM_ENABLED_PATH)?)
+}
+
+/// Prints the transparent hugepage setting for shared memory. On Linux 6.11 and later, the
+/// setting for PMD sized pages in `hugepages-2048kB/shmem_enabled` is printed too, since it
+/// overrides the global setting unless it is inherit.
+pub fn print_shmem_hugepage_setting(fs: &KernelFs, page_sizes: PageSizes) -> Result<(), Error> {
+    let setting = read_shmem_hugepage_setting(fs)?;
+    println!(
+        "shmem_enabled setting: {setting}: shared memory {}",
+        setting.description()
+    );
+
+    let pmd_size_path = format!(
+        "/sys/kernel/mm/transparent_hugepage/hugepages-{}kB/shmem_enabled",
+        page_sizes.huge >> 10
+    );
+    if let Ok(v) = fs.read(&pmd_size_path) {
+        let pmd_size_setting = anyos_hugepages::parse_selected_setting(&v)?;
+        println!(
+            "  {pmd_size_path}: {}",
+            String::from_utf8_lossy(pmd_size_setting)
+        );
+    }
+    Ok(())
+}
+
+// See: https://www.kernel.org/doc/Documentation/admin-guide/mm/hugetlbpage.rst
+const HUGETLB_PAGES_PATH: &str = "/sys/kernel/mm/hugepages";
+
+/// A hugetlb page size supported by the kernel, and the number of pages reserved for it.
+pub struct HugetlbPool {
+    pub page_size: usize,
+    pub nr_hugepages: usize,
+    pub free_hugepages: usize,
+    /// Free pages that mappings have reserved but not yet faulted in, which are not available to
+    /// new mappings.
+    pub resv_hugepages: usize,
+}
+
+impl HugetlbPool {
+    /// Returns the number of pages a new mapping can reserve.
+    #[must_use]
+    pub const fn available_hugepages(&self) -> usize {
+        self.free_hugepages.saturating_sub(self.resv_hugepages)
+    }
+
+    /// Returns why a mapping of `size_bytes` cannot use this pool, or None if it can. Pages larger
+    /// than the mapping are rejected because mmap rounds the length up, so the munmap of
+    /// `size_bytes` fails.
+    pub fn unusable_reason(&self, size_bytes: usize) -> Option<String> {
+        if self.page_size > size_bytes {
+            return Some(format!(
+                "pages are larger than the {} mapping",
+                page_size_string(size_bytes)
+            ));
+        }
+        let needed = size_bytes.div_ceil(self.page_size);
+        let available = self.available_hugepages();
+        if available < needed {
+            return Some(format!(
+                "needs {needed} available pages; has {available} ({} free, {} reserved); allocate them with: echo {} | sudo tee /sys/kernel/mm/hugepages/hugepages-{}kB/nr_hugepages",
+                self.free_hugepages,
+                self.resv_hugepages,
+                self.nr_hugepages + needed - available,
+                self.page_size >> 10
+            ));
+        }
+        None
+    }
+}
+
+/// Returns the hugetlb page sizes supported by the kernel, sorted from smallest to largest.
+pub fn read_hugetlb_pools(fs: &KernelFs) -> Result<Vec<HugetlbPool>, Error> {
+    let mut pools = Vec::new();
+    for name in fs.read_dir_names(HUGETLB_PAGES_PATH)? {
+        let Some(page_size) = anyos_hugepages::parse_hugetlb_dir_name(&name) else {
+            continue;
+        };
+        let pool_path = format!("{HUGETLB_PAGES_PATH}/{name}");
+        pools.push(HugetlbPool {
+            page_size,
+            nr_hugepages: fs.read_usize(&format!("{pool_path}/nr_hugepages"))?,
+            free_hugepages: fs.read_usize(&format!("{pool_path}/free_hugepages"))?,
+            resv_hugepages: fs.read_usize(&format!("{pool_path}/resv_hugepages"))?,
+        });
+    }
+    pools.sort_by_key(|pool| pool.page_size);
+    Ok(pools)
+}
+
+/// Returns the bits that select hugetlb pages of `page_size` in mmap or `memfd_create` flags. The
+/// size is encoded as log2(size) in the bits starting at `HUGETLB_FLAG_ENCODE_SHIFT`, which is how
+/// `MAP_HUGE_2MB` and `MFD_HUGE_2MB` etc. are defined.
+fn hugetlb_flag_encode(page_size: usize) -> u32 {
+    // https://github.com/torvalds/linux/blob/master/include/uapi/asm-generic/hugetlb_encode.h
+    const HUGETLB_FLAG_ENCODE_SHIFT: u32 = 26;
+    assert!(page_size.is_power_of_two());
+    page_size.trailing_zeros() << HUGETLB_FLAG_ENCODE_SHIFT
+}
+
+/// Returns the mmap flags to map hugetlb pages of `page_size`.
+pub fn map_huge_flags(page_size: usize) -> MapFlags {
+    MapFlags::MAP_HUGETLB | MapFlags::from_bits_retain(hugetlb_flag_encode(page_size).cast_signed())
+}
+
+/// Returns the `memfd_create` flags to create a file backed by hugetlb pages of `page_size`.
+fn memfd_huge_flags(page_size: usize) -> MFdFlags {
+    MFdFlags::MFD_HUGETLB | MFdFlags::from_bits_retain(hugetlb_flag_encode(page_size))
+}
+
+/// Creates an anonymous in-memory file of size bytes with `memfd_create`. If `hugetlb_page_size`
+/// is Some, the file is backed by hugetlb pages of that size; otherwise it is backed by shmem
+/// (tmpfs) pages.
+pub fn memfd_create_sized(
+    name: &str,
+    size: usize,
+    hugetlb_page_size: Option<usize>,
+) -> Result<OwnedFd, Error> {
+    let flags = hugetlb_page_size.map_or(MFdFlags::empty(), memfd_huge_flags);
+    let fd =
+        nix::sys::memfd::memfd_create(name, MFdFlags::MFD_CLOEXEC | flags).map_err(|errno| {
+            Error::Os {
+                call: "memfd_create",
+                errno,
+            }
+        })?;
+    let ftruncate_error = |errno| Error::Os {
+        call: "ftruncate",
+        errno,
+    };
+    let length = size
+        .try_into()
+        .map_err(|_| ftruncate_error(nix::Error::EFBIG))?;
+    nix::unistd::ftruncate(&fd, length).map_err(ftruncate_error)?;
+    Ok(fd)
+}
+
+/// Calls madvise with advice on slice. If `touch` is true, it then writes to every page to fault
+/// it in. Returns [`Error::UnsupportedAdvice`] if the kernel does not support transparent huge
+/// pages.
+pub fn madvise_hugepages_on_linux(
+    slice: &mut [u64],
+    advice: HugepageAdvice,
+    touch: bool,
+) -> Result<(), Error> {
+    let slice_pointer = NonNull::new(slice.as_mut_ptr().cast::<c_void>()).unwrap();
+    let slice_byte_len = slice.len() * 8;
+    let (name, madvise_advice) = match advice {
+        HugepageAdvice::Hugepage => ("madvise(MADV_HUGEPAGE)", MmapAdvise::MADV_HUGEPAGE),
+        HugepageAdvice::NoHugepage => ("madvise(MADV_NOHUGEPAGE)", MmapAdvise::MADV_NOHUGEPAGE),
+    };
+    let result = unsafe { nix::sys::mman::madvise(slice_pointer, slice_byte_len, madvise_advice) };
+    match result {
+        Ok(()) => {}
+        // returned when the kernel is built without CONFIG_TRANSPARENT_HUGEPAGE
+        Err(nix::Error::EINVAL) => {
+            return Err(Error::UnsupportedAdvice {
+                advice: name,
+                errno: nix::Error::EINVAL,
+                requires: "a kernel with CONFIG_TRANSPARENT_HUGEPAGE".to_string(),
+            });
+        }
+        Err(errno) => return Err(Error::Os { call: name, errno }),
+    }
+
+    if touch {
+        anyos_hugepages::touch_pages(slice);
+    }
+    Ok(())
+}
+
+/// Returns the mmap flags that fault in the entire mapping when it is created.
+pub const fn map_populate_flags() -> MapFlags {
+    MapFlags::MAP_POPULATE
+}
+
+/// Faults in every page of slice as writable with `madvise(MADV_POPULATE_WRITE)`, without
+/// writing to it. Requires Linux 5.14 or later.
+pub fn madvise_populate_write(slice: &mut [u64]) -> Result<(), Error> {
+    const ADVICE: &str = "madvise(MADV_POPULATE_WRITE)";
 
     let slice_pointer = NonNull::new(slice.as_mut_ptr().cast::<c_void>()).unwrap();
     let slice_byte_len = slice.len() * 8;
-    unsafe {
-        nix::sys::mman::madvise(slice_pointer, slice_byte_len, HUGEPAGE_FLAGS)
-            .expect("BUG: madvise must succeed");
+    let result = unsafe {
+        nix::sys::mman::madvise(
+            slice_pointer,
+            slice_byte_len,
+            MmapAdvise::MADV_POPULATE_WRITE,
+        )
+    };
+    match result {
+        Ok(()) => Ok(()),
+        Err(nix::Error::EINVAL) => Err(Error::UnsupportedAdvice {
+            advice: ADVICE,
+            errno: nix::Error::EINVAL,
+            requires: "Linux 5.14 or later".to_string(),
+        }),
+        Err(errno) => Err(Error::Os {
+            call: ADVICE,
+            errno,
+        }),
+    }
+}
+
+/// Synchronously collapses the pages of slice into transparent huge pages with
+/// `madvise(MADV_COLLAPSE)`, independent of the transparent hugepage setting. Requires Linux 6.1
+/// or later. The kernel collapses as much as it can, so if it cannot allocate huge pages (ENOMEM
+/// or EAGAIN), this prints a message and returns Ok; callers should check the coverage.
+pub fn madvise_collapse(slice: &mut [u64]) -> Result<(), Error> {
+    const ADVICE: &str = "madvise(MADV_COLLAPSE)";
+
+    let slice_byte_len = slice.len() * 8;
+    // nix does not define MADV_COLLAPSE
+    let result = unsafe {
+        libc::madvise(
+            slice.as_mut_ptr().cast::<c_void>(),
+            slice_byte_len,
+            libc::MADV_COLLAPSE,
+        )
+    };
+    if result == 0 {
+        return Ok(());
+    }
+    match nix::Error::last() {
+        nix::Error::EINVAL => {
+            let release = nix::sys::utsname::uname().map_err(|errno| Error::Os {
+                call: "uname",
+                errno,
+            })?;
+            Err(Error::UnsupportedAdvice {
+                advice: ADVICE,
+                errno: nix::Error::EINVAL,
+                requires: format!(
+                    "Linux 6.1 or later (running {}), and transparent huge pages not disabled by prctl (currently {})",
+                    release.release().to_string_lossy(),
+                    thp_disable_string()?
+                ),
+            })
+        }
+        err @ (nix::Error::ENOMEM | nix::Error::EAGAIN) => {
+            println!("  {ADVICE} did not collapse the entire region: {err}");
+            Ok(())
+        }
+        errno => Err(Error::Os {
+            call: ADVICE,
+            errno,
+        }),
     }
+}
+
+// PR_THP_DISABLE_EXCEPT_ADVISED from include/uapi/linux/prctl.h; not yet defined by libc
+const PR_THP_DISABLE_EXCEPT_ADVISED: libc::c_ulong = 1 << 1;
 
-    anyos_hugepages::touch_pages(slice);
+/// Pins the calling thread to cpu with `sched_setaffinity(2)`. Child processes inherit it.
+pub fn set_cpu_affinity(cpu: usize) -> Result<(), Error> {
+    let mut cpu_set = CpuSet::new();
+    cpu_set.set(cpu).map_err(|errno| Error::Os {
+        call: "CPU_SET",
+        errno,
+    })?;
+    sched_setaffinity(Pid::from_raw(0), &cpu_set).map_err(|errno| Error::Os {
+        call: "sched_setaffinity",
+        errno,
+    })
+}
+
+/// Disables transparent huge pages for this process and its children with
+/// `prctl(PR_SET_THP_DISABLE)`. If `except_advised` is true, regions with `MADV_HUGEPAGE` can still
+/// use them, which requires Linux 6.18 or later.
+pub fn set_thp_disable(except_advised: bool) -> Result<(), Error> {
+    let flags = if except_advised {
+        PR_THP_DISABLE_EXCEPT_ADVISED
+    } else {
+        0
+    };
+    let result = unsafe {
+        libc::prctl(
+            libc::PR_SET_THP_DISABLE,
+            1 as libc::c_ulong,
+            flags,
+            0 as libc::c_ulong,
+            0 as libc::c_ulong,
+        )
+    };
+    if result == 0 {
+        return Ok(());
+    }
+    match nix::Error::last() {
+        nix::Error::EINVAL if except_advised => Err(Error::UnsupportedAdvice {
+            advice: "prctl(PR_SET_THP_DISABLE, PR_THP_DISABLE_EXCEPT_ADVISED)",
+            errno: nix::Error::EINVAL,
+            requires: "Linux 6.18 or later".to_string(),
+        }),
+        errno => Err(Error::Os {
+            call: "prctl(PR_SET_THP_DISABLE)",
+            errno,
+        }),
+    }
+}
+
+/// Returns a description of the transparent huge page policy set by `prctl(PR_SET_THP_DISABLE)`.
+pub fn thp_disable_string() -> Result<String, Error> {
+    let result = unsafe {
+        libc::prctl(
+            libc::PR_GET_THP_DISABLE,
+            0 as libc::c_ulong,
+            0 as libc::c_ulong,
+            0 as libc::c_ulong,
+            0 as libc::c_ulong,
+        )
+    };
+    let value = nix::Error::result(result).map_err(|errno| Error::Os {
+        call: "prctl(PR_GET_THP_DISABLE)",
+        errno,
+    })?;
+    // returns 1 | flags if disabled
+    let description = if value == 0 {
+        "enabled"
+    } else if value as libc::c_ulong & PR_THP_DISABLE_EXCEPT_ADVISED != 0 {
+        "disabled except with MADV_HUGEPAGE"
+    } else {
+        "disabled"
+    };
+    Ok(description.to_string())
 }
 
 // See https://www.kernel.org/doc/Documentation/vm/pagemap.txt for
 // format which these bitmasks refer to
 // #define PAGEMAP_PRESENT(ent) (((ent) & (1ull << 63)) != 0)
+// #define PAGEMAP_SWAPPED(ent) (((ent) & (1ull << 62)) != 0)
 // #define PAGEMAP_PFN(ent) ((ent) & ((1ull << 55) - 1))
 
 /// Represents an entry in /proc/self/pagemap documented by:
@@ -60,6 +386,11 @@ impl PagemapEntry {
         self.v & (1 << 63) != 0
     }
 
+    const fn swapped(&self) -> bool {
+        // bit 62
+        self.v & (1 << 62) != 0
+    }
+
     const fn page_frame_number(&self) -> u64 {
         // bit 0-54 inclusive
         const MASK: u64 = (1 << 55) - 1;
@@ -67,79 +398,488 @@ impl PagemapEntry {
     }
 }
 
-/// Returns the best guess at the page size for the address pointed at by p.
-/// This needs to run as root to work correctly. This function will print
-/// detailed debugging output.
-pub fn read_page_size(p: usize) -> Result<usize, std::io::Error> {
-    const PAGEMAP_PATH: &str = "/proc/self/pagemap";
-    const KPAGEFLAGS_PATH: &str = "/proc/kpageflags";
+const PAGEMAP_PATH: &str = "/proc/self/pagemap";
 
-    // KPF_THP https://github.com/torvalds/linux/blob/master/include/uapi/linux/kernel-page-flags.h
-    const KPAGEFLAGS_THP_BIT: u64 = 22;
+/// Returns the page frame number of each base page in the len bytes starting at start, or None if
+/// the page is not present. Requires root, since otherwise the kernel reports every page frame
+/// number as zero.
+pub fn read_pagemap_pfns(
+    fs: &KernelFs,
+    page_sizes: PageSizes,
+    start: usize,
+    len: usize,
+) -> Result<Vec<Option<u64>>, Error> {
+    let read_error = |err| fs.io_error(&err, PAGEMAP_PATH);
+    let mut pagemap_f = fs.open(PAGEMAP_PATH)?;
+    let page_size = page_sizes.base;
+    let first_page = start / page_size;
+    let end_page = (start + len).div_ceil(page_size);
+    pagemap_f
+        .seek(SeekFrom::Start(first_page as u64 * 8))
+        .map_err(read_error)?;
+    let mut entry_bytes = vec![0u8; (end_page - first_page) * 8];
+    pagemap_f.read_exact(&mut entry_bytes).map_err(read_error)?;
 
-    let mut pagemap_f = File::open(PAGEMAP_PATH)?;
+    let mut pfns = Vec::with_capacity(end_page - first_page);
+    for chunk in entry_bytes.chunks_exact(8) {
+        let entry = PagemapEntry::from_bytes(chunk.try_into().unwrap());
+        if !entry.present() {
+            pfns.push(None);
+            continue;
+        }
+        // page frame 0 is never a user page: it is only reported when page frames are hidden
+        if entry.page_frame_number() == 0 {
+            return Err(Error::PermissionDenied {
+                path: PAGEMAP_PATH.to_string(),
+            });
+        }
+        pfns.push(Some(entry.page_frame_number()));
+    }
+    Ok(pfns)
+}
+
+/// Returns the best guess at the page size for the address pointed at by p, and how it was
+/// found. This uses the page's flags from kpageflags if this process can see page frame numbers,
+/// which requires root outside a container. Otherwise it uses the mapping's counters from smaps.
+/// This function will print detailed debugging output. Returns [`Error::NotPresent`] or
+/// [`Error::Swapped`] if the page is not in memory.
+pub fn read_page_size(
+    fs: &KernelFs,
+    page_sizes: PageSizes,
+    p: usize,
+) -> Result<(usize, PageSizeBackend), Error> {
+    match read_kpageflags_page_size(fs, page_sizes, p) {
+        Ok(Some(page_size)) => return Ok((page_size, PageSizeBackend::Kpageflags)),
+        Ok(None) => println!(
+            "  page frame number is zero: requires root outside a container; using /proc/self/smaps"
+        ),
+        Err(Error::PermissionDenied { path }) => {
+            println!("  permission denied reading {path}; using /proc/self/smaps");
+        }
+        Err(err) => return Err(err),
+    }
+    Ok((
+        read_smaps_page_size(fs, page_sizes, p)?,
+        PageSizeBackend::Smaps,
+    ))
+}
+
+/// Returns the page size for p from the flags of its page frame in `/proc/kpageflags`. Returns
+/// None if the page frame number is hidden, because this process is not root.
+fn read_kpageflags_page_size(
+    fs: &KernelFs,
+    page_sizes: PageSizes,
+    p: usize,
+) -> Result<Option<usize>, Error> {
+    let read_error = |err| fs.io_error(&err, PAGEMAP_PATH);
+    let mut pagemap_f = fs.open(PAGEMAP_PATH)?;
 
     // Each pagemap entry is 8 bytes / 64 bits
     // There is one entry for each base page size
     // https://www.kernel.org/doc/Documentation/vm/pagemap.txt
-    let page_size = anyos_hugepages::sysconf_page_size();
+    let page_size = page_sizes.base;
     let offset = p / page_size * 8;
-    pagemap_f.seek(SeekFrom::Start(offset as u64))?;
+    pagemap_f
+        .seek(SeekFrom::Start(offset as u64))
+        .map_err(read_error)?;
 
     let mut entry_bytes = [0u8; 8];
-    pagemap_f.read_exact(&mut entry_bytes[..])?;
+    pagemap_f
+        .read_exact(&mut entry_bytes[..])
+        .map_err(read_error)?;
     let entry = PagemapEntry::from_bytes(entry_bytes);
-    assert!(entry.present(), "page for p=0x{p:x?} not allocated");
+    if entry.swapped() {
+        return Err(Error::Swapped { address: p });
+    }
+    if !entry.present() {
+        return Err(Error::NotPresent { address: p });
+    }
 
     if entry.page_frame_number() == 0 {
+        return Ok(None);
+    }
+
+    let pfn = entry.page_frame_number();
+    let flags = hugepagedemo::read_kpageflags(fs, pfn, 1)?[0];
+    let map_count = hugepagedemo::read_kpagecount(fs, pfn, 1)?[0];
+    println!("  pfn 0x{pfn:x} kpageflags: {flags}; kpagecount: {map_count}");
+
+    // huge pages are compound pages: the first base page is the head, and the rest are tails
+    let compound = if flags.contains(KPageFlags::COMPOUND_HEAD) {
+        "compound head"
+    } else if flags.contains(KPageFlags::COMPOUND_TAIL) {
+        "compound tail"
+    } else {
+        "not compound"
+    };
+
+    if flags.contains(KPageFlags::HUGE) {
+        println!("  kpageflags HUGE bit is set ({compound}): is a hugetlb page!");
+        // the hugetlb page size is a property of the mapping
+        return read_kernel_page_size(fs, p).map(Some);
+    }
+    if flags.contains(KPageFlags::THP) {
+        println!("  kpageflags THP bit is set ({compound})");
+        let huge_start = p & !(page_sizes.huge - 1);
+        let pfns = read_pagemap_pfns(fs, page_sizes, huge_start, page_sizes.huge)?;
+        let start_vpn = (huge_start / page_size) as u64;
+        if is_pmd_sized_thp(&pfns, start_vpn, |pfn, count| {
+            hugepagedemo::read_kpageflags(fs, pfn, count)
+        })? {
+            println!("  the aligned range is one compound page: is a huge page!");
+            return Ok(Some(page_sizes.huge));
+        }
         println!(
-            "  page frame number is zero; must run as root outside a container; assuming default page size"
+            "  the aligned range is not one compound page: is a multi-size THP folio smaller than {}",
+            page_size_string(page_sizes.huge)
         );
-        return Ok(page_size);
+        return Ok(Some(page_size));
     }
 
-    let mut kpageflags_f = File::open(KPAGEFLAGS_PATH)?;
-    let offset = entry.page_frame_number() * 8;
-    kpageflags_f.seek(SeekFrom::Start(offset))?;
-    kpageflags_f.read_exact(&mut entry_bytes)?;
+    println!("  kpageflags does not have THP or HUGE bits set ({compound}); not a huge page");
+    Ok(Some(page_size))
+}
 
-    let kpageflag_entry = u64::from_le_bytes(entry_bytes);
-    if (kpageflag_entry & (1 << KPAGEFLAGS_THP_BIT)) == 0 {
-        println!("  kpageflags does not have THP bit set; not a huge page");
-        return Ok(page_size);
+/// Returns true if pfns, the page frames of one naturally aligned huge page sized virtual range
+/// starting at `start_vpn`, are one transparent huge page. The kpageflags THP bit is also set for
+/// multi-size THP folios, which are smaller and mapped with base pages, so this requires the page
+/// frames to be physically contiguous and aligned, and `read_flags(pfn, count)` to return one
+/// compound page: a head followed by only tails.
+fn is_pmd_sized_thp(
+    pfns: &[Option<u64>],
+    start_vpn: u64,
+    read_flags: impl FnOnce(u64, usize) -> Result<Vec<KPageFlags>, Error>,
+) -> Result<bool, Error> {
+    if PfnRuns::new(pfns, start_vpn, pfns.len()).aligned_huge_ranges != 1 {
+        return Ok(false);
     }
+    let head_pfn = pfns[0].expect("BUG: an aligned huge range must be present");
+    let flags = read_flags(head_pfn, pfns.len())?;
+    let (head, tails) = flags
+        .split_first()
+        .expect("BUG: an aligned huge range must not be empty");
+    Ok(head.contains(KPageFlags::COMPOUND_HEAD | KPageFlags::THP)
+        && tails
+            .iter()
+            .all(|tail| tail.contains(KPageFlags::COMPOUND_TAIL | KPageFlags::THP)))
+}
+
+/// Returns the mapping in `/proc/self/smaps` that contains p.
+fn read_smaps_mapping(fs: &KernelFs, p: usize) -> Result<SmapsMapping, Error> {
+    hugepagedemo::read_smaps(fs)?
+        .into_iter()
+        .find(|mapping| mapping.overlaps(p, p + 1))
+        .ok_or_else(|| Error::parse("/proc/self/smaps", format!("no mapping contains p=0x{p:x}")))
+}
 
-    println!("  kpageflags THP bit is set: is a huge page!");
+/// Returns the `KernelPageSize` from `/proc/self/smaps` for the mapping containing p. This is the
+/// hugetlb page size for hugetlb mappings, and the base page size otherwise.
+fn read_kernel_page_size(fs: &KernelFs, p: usize) -> Result<usize, Error> {
+    read_smaps_mapping(fs, p)?
+        .field("KernelPageSize")
+        .ok_or_else(|| {
+            Error::parse(
+                "/proc/self/smaps",
+                format!("no KernelPageSize for p=0x{p:x}"),
+            )
+        })
+}
 
-    // Read the size of the huge page from /sys/kernel/mm/transparent_hugepage/hpage_pmd_size
-    read_hugepage_size()
+/// Returns the page size for p from the mapping containing it in `/proc/self/smaps`, which does
+/// not require root. See [`smaps_page_size`].
+fn read_smaps_page_size(fs: &KernelFs, page_sizes: PageSizes, p: usize) -> Result<usize, Error> {
+    let mapping = read_smaps_mapping(fs, p)?;
+    smaps_page_size(&mapping, page_sizes.base, page_sizes.huge).ok_or_else(|| {
+        Error::parse(
+            "/proc/self/smaps",
+            format!("no KernelPageSize for p=0x{p:x}"),
+        )
+    })
 }
 
-fn read_hugepage_size() -> Result<usize, std::io::Error> {
-    const HPAGE_PMD_SIZE_PATH: &str = "/sys/kernel/mm/transparent_hugepage/hpage_pmd_size";
-    let mut hpage_size_string = std::fs::read_to_string(HPAGE_PMD_SIZE_PATH)?;
-    // always terminated by \n
-    if hpage_size_string.ends_with('\n') {
-        hpage_size_string.pop();
+/// Returns the page size for an address in mapping. hugetlb mappings report their page size as
+/// `KernelPageSize`. Transparent huge pages are only counted for the entire mapping, in
+/// `AnonHugePages` or `ShmemPmdMapped`, so this returns `hugepage_size` if most of the mapping's
+/// resident memory is huge pages. Returns None if the mapping has no `KernelPageSize`.
+fn smaps_page_size(
+    mapping: &SmapsMapping,
+    base_page_size: usize,
+    hugepage_size: usize,
+) -> Option<usize> {
+    let kernel_page_size = mapping.field("KernelPageSize")?;
+    if kernel_page_size > base_page_size {
+        println!("  smaps KernelPageSize={kernel_page_size}: is a hugetlb page!");
+        return Some(kernel_page_size);
     }
 
-    let hpage_size_result = hpage_size_string.parse::<usize>();
-    match hpage_size_result {
-        Err(err) => {
-            let msg = format!("  failed to parse {HPAGE_PMD_SIZE_PATH}: {err:?}");
-            Err(std::io::Error::new(std::io::ErrorKind::Other, msg))
-        }
-        Ok(hpage_size) => Ok(hpage_size),
+    let huge_bytes =
+        mapping.field("AnonHugePages").unwrap_or(0) + mapping.field("ShmemPmdMapped").unwrap_or(0);
+    let rss = mapping.field("Rss").unwrap_or(0);
+    println!(
+        "  smaps mapping 0x{:x}-0x{:x}: huge pages {huge_bytes} of Rss {rss} ({:.1}%)",
+        mapping.start,
+        mapping.end,
+        huge_bytes as f64 / rss.max(1) as f64 * 100.0
+    );
+    if huge_bytes > 0 && huge_bytes * 2 >= rss {
+        println!("  most of the mapping is huge pages; assuming this address is a huge page");
+        return Some(hugepage_size);
     }
+    Some(kernel_page_size)
 }
 
 #[cfg(all(test, target_os = "linux"))]
 mod test {
     use super::*;
 
+    /// Returns the fixture directory in the library's testdata described by name.
+    fn fixture(name: &str) -> KernelFs {
+        KernelFs::new(
+            std::path::Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/testdata")).join(name),
+        )
+    }
+
     #[test]
-    fn test_read_hugepage_size() {
-        // this is not always true, but true for x86_64 and current aarch64 platforms
-        assert_eq!(2048 * 1024, read_hugepage_size().unwrap());
+    fn test_read_hugepage_settings() {
+        let fs = fixture("x86_64");
+        assert_eq!(
+            HugepageSetting::MAdvise,
+            read_hugepage_setting(&fs).unwrap()
+        );
+        assert_eq!(
+            ShmemHugepageSetting::Never,
+            read_shmem_hugepage_setting(&fs).unwrap()
+        );
+        print_shmem_hugepage_setting(&fs, PageSizes::read_with_base(&fs, 4 << 10).unwrap())
+            .unwrap();
+
+        let fs = fixture("synthetic-arm64-64k");
+        assert_eq!(
+            HugepageSetting::MAdvise,
+            read_hugepage_setting(&fs).unwrap()
+        );
+        print_shmem_hugepage_setting(&fs, PageSizes::read_with_base(&fs, 64 << 10).unwrap())
+            .unwrap();
+
+        let fs = fixture("thp-never");
+        assert_eq!(HugepageSetting::Never, read_hugepage_setting(&fs).unwrap());
+
+        // a kernel without CONFIG_TRANSPARENT_HUGEPAGE has none of these files
+        let fs = fixture("missing");
+        assert!(read_hugepage_setting(&fs).is_err());
+        let page_sizes = PageSizes::read_with_base(&fs, 4 << 10).unwrap();
+        assert!(print_shmem_hugepage_setting(&fs, page_sizes).is_err());
+    }
+
+    #[test]
+    fn test_read_hugetlb_pools() {
+        let pools = |name| {
+            read_hugetlb_pools(&fixture(name))
+                .unwrap()
+                .iter()
+                .map(|pool| {
+                    (
+                        pool.page_size,
+                        pool.nr_hugepages,
+                        pool.free_hugepages,
+                        pool.resv_hugepages,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            vec![(2 << 20, 16, 16, 8), (1 << 30, 0, 0, 0)],
+            pools("x86_64")
+        );
+        assert_eq!(
+            vec![
+                (2 << 20, 0, 0, 0),
+                (512 << 20, 8, 0, 0),
+                (16 << 30, 0, 0, 0)
+            ],
+            pools("synthetic-arm64-64k")
+        );
+        assert_eq!(
+            vec![(2 << 20, 16, 16, 8), (1 << 30, 0, 0, 0)],
+            pools("thp-never")
+        );
+
+        let reasons = |name, size_bytes| {
+            read_hugetlb_pools(&fixture(name))
+                .unwrap()
+                .iter()
+                .map(|pool| pool.unusable_reason(size_bytes))
+                .collect::<Vec<_>>()
+        };
+        let x86_reasons = reasons("x86_64", 16 << 20);
+        assert_eq!(None, x86_reasons[0]);
+        assert_eq!(
+            Some("pages are larger than the 16M mapping"),
+            x86_reasons[1].as_deref()
+        );
+        // all 16 pages are free, but another process reserved 8 of them
+        let x86_reasons = reasons("x86_64", 32 << 20);
+        assert_eq!(
+            Some(
+                "needs 16 available pages; has 8 (16 free, 8 reserved); allocate them with: echo 24 | sudo tee /sys/kernel/mm/hugepages/hugepages-2048kB/nr_hugepages"
+            ),
+            x86_reasons[0].as_deref()
+        );
+        let x86_reasons = reasons("x86_64", 4 << 30);
+        assert!(
+            x86_reasons[0]
+                .as_ref()
+                .unwrap()
+                .starts_with("needs 2048 available pages; has 8 (16 free, 8 reserved)")
+        );
+        assert!(
+            x86_reasons[1]
+                .as_ref()
+                .unwrap()
+                .starts_with("needs 4 available pages; has 0 (0 free, 0 reserved)")
+        );
+        let arm64_reasons = reasons("synthetic-arm64-64k", 4 << 30);
+        assert!(
+            arm64_reasons[1]
+                .as_ref()
+                .unwrap()
+                .starts_with("needs 8 available pages; has 0")
+        );
+        assert_eq!(
+            Some("pages are larger than the 4G mapping"),
+            arm64_reasons[2].as_deref()
+        );
+    }
+
+    #[test]
+    fn test_read_kernel_page_size() {
+        let v = vec![0x42u64; 1024];
+        assert_eq!(
+            hugepagedemo::sysconf_page_size(),
+            read_kernel_page_size(&KernelFs::default(), v.as_ptr() as usize).unwrap()
+        );
+
+        // a hugetlb mapping and a transparent huge page mapping
+        let fs = fixture("synthetic-arm64-64k");
+        assert_eq!(
+            512 << 20,
+            read_kernel_page_size(&fs, 0xfffd_0000_0000).unwrap()
+        );
+        let page_sizes = PageSizes::read_with_base(&fs, 64 << 10).unwrap();
+        for (address, expected) in [
+            (0xfffd_0000_0000, 512 << 20),
+            (0xfffe_4000_0000, 512 << 20),
+            (0xffff_f2a2_0000, 64 << 10),
+        ] {
+            assert_eq!(
+                expected,
+                read_smaps_page_size(&fs, page_sizes, address).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_smaps_page_size() {
+        const SMAPS: &[u8] = b"7f4c00000000-7f4d00000000 rw-p 00000000 00:00 0
+Rss:             4194304 kB
+KernelPageSize:        4 kB
+AnonHugePages:   4192256 kB
+7f4e00000000-7f4f00000000 rw-p 00000000 00:00 0
+Rss:             4194304 kB
+KernelPageSize:        4 kB
+AnonHugePages:     32768 kB
+7f5000000000-7f5100000000 rw-s 00000000 00:01 1234                       /memfd:hugepagedemo (deleted)
+Rss:                   0 kB
+KernelPageSize:     2048 kB
+AnonHugePages:         0 kB
+7f5200000000-7f5300000000 rw-s 00000000 00:01 1235                       /memfd:hugepagedemo (deleted)
+Rss:             4194304 kB
+KernelPageSize:        4 kB
+ShmemPmdMapped:  4194304 kB
+";
+        const BASE: usize = 4 << 10;
+        const HUGE: usize = 2 << 20;
+        let mappings = hugepagedemo::parse_smaps(SMAPS).unwrap();
+        let page_sizes = mappings
+            .iter()
+            .map(|mapping| smaps_page_size(mapping, BASE, HUGE))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![Some(HUGE), Some(BASE), Some(HUGE), Some(HUGE)],
+            page_sizes
+        );
+
+        let mappings = hugepagedemo::parse_smaps(b"7f4c00000000-7f4d00000000 rw-p\n").unwrap();
+        assert_eq!(None, smaps_page_size(&mappings[0], BASE, HUGE));
+    }
+
+    #[test]
+    fn test_is_pmd_sized_thp() {
+        const HUGE_PAGES: usize = 512;
+        const MTHP_PAGES: usize = 16;
+        let thp = |head| {
+            if head {
+                KPageFlags::ANON | KPageFlags::COMPOUND_HEAD | KPageFlags::THP
+            } else {
+                KPageFlags::ANON | KPageFlags::COMPOUND_TAIL | KPageFlags::THP
+            }
+        };
+        // the kpageflags of page frames 0-2047: a huge page at 512, and 64 KiB multi-size THP
+        // folios from 1024 to 2047
+        let mut kpageflags = vec![KPageFlags::empty(); 4 * HUGE_PAGES];
+        for (i, flags) in kpageflags[HUGE_PAGES..2 * HUGE_PAGES]
+            .iter_mut()
+            .enumerate()
+        {
+            *flags = thp(i == 0);
+        }
+        for (i, flags) in kpageflags[2 * HUGE_PAGES..].iter_mut().enumerate() {
+            *flags = thp(i % MTHP_PAGES == 0);
+        }
+        let read_flags =
+            |pfn: u64, count: usize| Ok(kpageflags[pfn as usize..pfn as usize + count].to_vec());
+        let pfns = |first_pfn: u64| (first_pfn..).take(HUGE_PAGES).map(Some).collect::<Vec<_>>();
+
+        // one mapping that contains both: only the huge page is PMD-sized
+        let start_vpn = 0x7f00_0000_0000 >> 12;
+        assert!(is_pmd_sized_thp(&pfns(512), start_vpn, read_flags).unwrap());
+        // physically contiguous and aligned, but many folios
+        assert!(!is_pmd_sized_thp(&pfns(1024), start_vpn + 512, read_flags).unwrap());
+        assert!(!is_pmd_sized_thp(&pfns(1536), start_vpn + 1024, read_flags).unwrap());
+
+        // not aligned or not contiguous: kpageflags is not read
+        let unused = |_, _| -> Result<Vec<KPageFlags>, Error> { panic!("must not be called") };
+        assert!(!is_pmd_sized_thp(&pfns(1040), start_vpn, unused).unwrap());
+        let mut not_present = pfns(512);
+        not_present[HUGE_PAGES - 1] = None;
+        assert!(!is_pmd_sized_thp(&not_present, start_vpn, unused).unwrap());
+    }
+
+    #[test]
+    fn test_map_huge_flags() {
+        assert_eq!(
+            MapFlags::MAP_HUGETLB | MapFlags::MAP_HUGE_2MB,
+            map_huge_flags(2 << 20)
+        );
+        assert_eq!(
+            MapFlags::MAP_HUGETLB | MapFlags::MAP_HUGE_1GB,
+            map_huge_flags(1 << 30)
+        );
+        assert_eq!(
+            MapFlags::MAP_HUGETLB | MapFlags::MAP_HUGE_64KB,
+            map_huge_flags(64 << 10)
+        );
+    }
+
+    #[test]
+    fn test_memfd_huge_flags() {
+        assert_eq!(
+            MFdFlags::MFD_HUGETLB | MFdFlags::MFD_HUGE_2MB,
+            memfd_huge_flags(2 << 20)
+        );
+        assert_eq!(
+            MFdFlags::MFD_HUGETLB | MFdFlags::MFD_HUGE_1GB,
+            memfd_huge_flags(1 << 30)
+        );
     }
 }
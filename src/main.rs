@@ -1,53 +1,186 @@
-use clap::Parser;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser};
+use hugepagedemo::KernelFs;
 use hugepagedemo::MmapOwner;
-use memory_stats::memory_stats;
+#[cfg(target_os = "linux")]
+use hugepagedemo::MmapRegion;
+use hugepagedemo::NumaPolicy;
+use hugepagedemo::PageSizes;
+#[cfg(target_os = "linux")]
+use hugepagedemo::PfnRuns;
+use hugepagedemo::ProcessMemory;
+use hugepagedemo::{AccessTraceReader, AccessTraceWriter};
+use hugepagedemo::{CollapseCurve, CollapseSample, KhugepagedStats};
+use hugepagedemo::{
+    TlbConfig, TlbSimulator, TlbStats, page_size_string, parse_duration, parse_page_size,
+};
 use nix::sys::mman::{MapFlags, ProtFlags};
 use rand::distr::Distribution;
-use rand::{RngCore, SeedableRng, distr::Uniform};
+use rand::rngs::SmallRng;
+use rand::{SeedableRng, distr::Uniform};
 use std::error::Error;
+use std::fs::File;
+#[cfg(target_os = "linux")]
+use std::io::BufRead;
+use std::io::{BufReader, BufWriter};
 use std::num::NonZeroUsize;
+#[cfg(target_os = "linux")]
+use std::os::fd::AsFd;
 use std::os::raw::c_void;
+use std::path::PathBuf;
+#[cfg(target_os = "linux")]
+use std::process::{Command, Stdio};
 use std::ptr::NonNull;
 use std::slice;
 use std::thread::sleep;
 use std::time::{Duration, Instant};
 
 mod anyos_hugepages;
-mod mmaputils;
-#[cfg(target_os = "linux")]
-use mmaputils::MmapRegion;
+use anyos_hugepages::{HugepageAdvice, PageSizeBackend};
 
 #[cfg(target_os = "linux")]
 mod linux_hugepages;
 #[cfg(target_os = "linux")]
+use linux_hugepages::madvise_collapse;
+#[cfg(target_os = "linux")]
 use linux_hugepages::madvise_hugepages_on_linux;
 #[cfg(target_os = "linux")]
+use linux_hugepages::madvise_populate_write;
+#[cfg(target_os = "linux")]
+use linux_hugepages::map_huge_flags;
+#[cfg(target_os = "linux")]
+use linux_hugepages::map_populate_flags;
+#[cfg(target_os = "linux")]
+use linux_hugepages::memfd_create_sized;
+#[cfg(target_os = "linux")]
 use linux_hugepages::print_hugepage_setting_on_linux;
 #[cfg(target_os = "linux")]
+use linux_hugepages::print_shmem_hugepage_setting;
+#[cfg(target_os = "linux")]
+use linux_hugepages::read_hugepage_setting;
+#[cfg(target_os = "linux")]
+use linux_hugepages::read_hugetlb_pools;
+#[cfg(target_os = "linux")]
 use linux_hugepages::read_page_size;
+#[cfg(target_os = "linux")]
+use linux_hugepages::read_pagemap_pfns;
+#[cfg(target_os = "linux")]
+use linux_hugepages::set_cpu_affinity;
+#[cfg(target_os = "linux")]
+use linux_hugepages::set_thp_disable;
+#[cfg(target_os = "linux")]
+use linux_hugepages::thp_disable_string;
 
 #[cfg(not(target_os = "linux"))]
 mod notlinux_hugepages;
 #[cfg(not(target_os = "linux"))]
 use notlinux_hugepages::madvise_hugepages_on_linux;
 #[cfg(not(target_os = "linux"))]
+use notlinux_hugepages::madvise_populate_write;
+#[cfg(not(target_os = "linux"))]
+use notlinux_hugepages::map_populate_flags;
+#[cfg(not(target_os = "linux"))]
 use notlinux_hugepages::print_hugepage_setting_on_linux;
 #[cfg(not(target_os = "linux"))]
 use notlinux_hugepages::read_page_size;
+#[cfg(not(target_os = "linux"))]
+use notlinux_hugepages::set_cpu_affinity;
+#[cfg(not(target_os = "linux"))]
+use notlinux_hugepages::set_thp_disable;
 
 const FILLED: u64 = 0x42;
 
 #[derive(Debug, Parser)]
 #[command(version, about, long_about = None)]
 /// Control the options for the huge page demo.
+#[allow(clippy::struct_excessive_bools)]
 struct HugePageDemoOptions {
     /// disable using mmap with madvise.
     #[arg(long, default_value_t = RunMode::All)]
     run_mode: RunMode,
 
-    /// sleep for 60 seconds before dropping the mmap, to allow examining the process state.
+    /// hugetlb page sizes for --run-mode=MmapHugeTLB or --run-mode=MemfdHugeTLB, e.g. 2M,1G.
+    /// Default: every size in /sys/kernel/mm/hugepages.
+    #[arg(long, value_delimiter = ',', value_parser = parse_page_size)]
+    hugetlb_page_size: Vec<usize>,
+
+    /// how the mmap run modes fault in memory before filling it; timed separately from the fill.
+    /// `MapPopulate` can only be used with the hugetlb run modes.
+    #[arg(long, default_value_t = Prefault::None)]
+    prefault: Prefault,
+
+    /// disable transparent huge pages for the process with `prctl(PR_SET_THP_DISABLE)` before
+    /// running any mode. Does not require root.
+    #[arg(long, default_value_t = ThpDisable::None)]
+    thp_disable: ThpDisable,
+
+    /// bytes past a transparent huge page boundary to start the mapping for
+    /// --run-mode=MmapMisaligned. Must be a multiple of the base page size.
+    #[arg(long, default_value_t = 1 << 20)]
+    misalign_offset: usize,
+
+    /// after each mode's benchmark, watch its memory until khugepaged stops collapsing it into
+    /// huge pages, and print how long it took. This also allows examining the process state.
+    /// --run-mode=MmapKhugepaged always watches.
     #[arg(long)]
-    sleep_before_drop: bool,
+    watch: bool,
+
+    /// time between samples of the huge page coverage and access rate for --watch.
+    #[arg(long, default_value = "5s", value_parser = parse_duration)]
+    watch_interval: Duration,
+
+    /// stop watching after this long, even if khugepaged is still collapsing pages.
+    #[arg(long, default_value = "1h", value_parser = parse_duration)]
+    watch_timeout: Duration,
+
+    /// print how physically contiguous each mode's memory is, from the page frame numbers in
+    /// /proc/self/pagemap. Requires root.
+    #[arg(long)]
+    pfn_runs: bool,
+
+    /// print the NUMA node of each mode's pages, from `move_pages(2)` and `/proc/self/numa_maps`.
+    #[arg(long)]
+    numa: bool,
+
+    /// NUMA memory policy for the mmap and memfd modes, set with `mbind(2)` before filling:
+    /// bind:NODES, interleave:NODES, preferred:NODE, or default, where NODES is like 0-1,3. The
+    /// Vec modes use the process policy. Default: do not call mbind.
+    #[arg(long)]
+    numa_policy: Option<NumaPolicy>,
+
+    /// pin the benchmark to this CPU with `sched_setaffinity(2)`. With --numa-policy, this
+    /// compares local and remote memory.
+    #[arg(long)]
+    cpu: Option<usize>,
+
+    /// generate the random indexes into a buffer before timing, so the timed loop only does loads.
+    #[arg(long)]
+    precompute_indexes: bool,
+
+    /// seed for the random indexes; every mode accesses the same indexes. Default: random.
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// write the indexes accessed by the first benchmark to this file.
+    #[arg(long, conflicts_with = "replay_trace")]
+    record_trace: Option<PathBuf>,
+
+    /// access the indexes in this file written by --record-trace, instead of random indexes.
+    #[arg(long)]
+    replay_trace: Option<PathBuf>,
+
+    /// simulate a TLB with the same accesses, and compare its predictions to the measurements.
+    #[arg(long)]
+    tlb_sim: bool,
+
+    /// TLB for --tlb-sim: levels separated by '/'; arrays in a level separated by ','; each array
+    /// is ENTRIESxWAYS:SIZES, with page sizes separated by '+'.
+    #[arg(long, default_value = TlbConfig::DEFAULT_SPEC)]
+    tlb_config: TlbConfig,
+
+    /// print each result as a tab separated line, for --run-mode=VecGlibcTunables to collect.
+    #[arg(long, hide = true)]
+    result_lines: bool,
 }
 
 #[derive(strum::Display, strum::EnumString, Eq, PartialEq, Debug, Clone)]
@@ -56,20 +189,145 @@ enum RunMode {
     VecOnly,
     MmapOnly,
     MmapHugeTLB1GiBOnly,
+    MmapHugeTLB,
+    MmapCollapse,
+    MmapKhugepaged,
+    VecMadvise,
+    MemfdHugeTLB,
+    MemfdShmemTHP,
+    MmapNoHugepage,
+    MmapMisaligned,
+    VecGlibcTunables,
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    const TEST_SIZE_GIB: usize = 4;
-    const TEST_SIZE_BYTES: usize = TEST_SIZE_GIB * 1024 * 1024 * 1024;
-    const TEST_SIZE_U64: usize = TEST_SIZE_BYTES / 8;
+/// How the mmap run modes fault in memory before it is filled.
+#[derive(strum::Display, strum::EnumString, Eq, PartialEq, Debug, Clone, Copy)]
+enum Prefault {
+    /// Fault in pages by writing to them.
+    None,
+    /// `mmap(MAP_POPULATE)`. Only for the hugetlb run modes: it populates the mapping before
+    /// `madvise(MADV_HUGEPAGE)`, so the transparent huge page modes would get base pages.
+    MapPopulate,
+    /// `madvise(MADV_POPULATE_WRITE)` after mmap and `madvise(MADV_HUGEPAGE)`.
+    MadvPopulateWrite,
+}
+
+/// The process-wide transparent huge page policy set with `prctl(PR_SET_THP_DISABLE)`.
+#[derive(strum::Display, strum::EnumString, Eq, PartialEq, Debug, Clone, Copy)]
+enum ThpDisable {
+    /// Do not call prctl: use the system setting.
+    None,
+    /// Never use transparent huge pages, even with `madvise(MADV_HUGEPAGE)`.
+    All,
+    /// Only use transparent huge pages for regions with `madvise(MADV_HUGEPAGE)`, like the
+    /// madvise setting. Requires Linux 6.18 or later.
+    ExceptAdvised,
+}
 
+impl HugePageDemoOptions {
+    /// Returns an error for combinations of options that would silently measure something other
+    /// than what was requested.
+    fn check_conflicts(&self) -> Result<(), String> {
+        if self.prefault != Prefault::MapPopulate {
+            return Ok(());
+        }
+        if self.run_mode.uses_thp_mmap() {
+            return Err(format!(
+                "--prefault=MapPopulate populates the mapping before madvise(MADV_HUGEPAGE), so --run-mode={} would only get base pages; use --prefault=MadvPopulateWrite",
+                self.run_mode
+            ));
+        }
+        if self.numa_policy.is_some() {
+            // mbind can only move pages after they are populated, and ignores failures to move
+            return Err("--prefault=MapPopulate populates the mapping before --numa-policy is applied with mbind; use --prefault=MadvPopulateWrite".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl RunMode {
+    /// Returns true if this mode maps memory that should use transparent huge pages, which must
+    /// be advised before it is populated.
+    const fn uses_thp_mmap(&self) -> bool {
+        matches!(
+            self,
+            Self::All
+                | Self::MmapOnly
+                | Self::MmapCollapse
+                | Self::MmapKhugepaged
+                | Self::MemfdShmemTHP
+                | Self::MmapNoHugepage
+                | Self::MmapMisaligned
+        )
+    }
+}
+
+impl Prefault {
+    const fn map_flags(self) -> MapFlags {
+        match self {
+            Self::MapPopulate => map_populate_flags(),
+            Self::None | Self::MadvPopulateWrite => MapFlags::empty(),
+        }
+    }
+}
+
+const TEST_SIZE_GIB: usize = 4;
+const TEST_SIZE_BYTES: usize = TEST_SIZE_GIB * 1024 * 1024 * 1024;
+const TEST_SIZE_U64: usize = TEST_SIZE_BYTES / 8;
+
+fn main() -> Result<(), Box<dyn Error>> {
     let options = HugePageDemoOptions::parse();
+    if let Err(message) = options.check_conflicts() {
+        HugePageDemoOptions::command()
+            .error(ErrorKind::ArgumentConflict, message)
+            .exit();
+    }
 
-    // the rand book suggests SmallRng is fast and pretty good:
-    // https://rust-random.github.io/book/guide-rngs.html
-    let mut rng = rand::rngs::SmallRng::from_os_rng();
+    let seed = options.seed.unwrap_or_else(rand::random);
+    println!("random seed: {seed} (reproduce with --seed={seed})");
+    if options.thp_disable != ThpDisable::None {
+        set_thp_disable(options.thp_disable == ThpDisable::ExceptAdvised)?;
+    }
+    if let Some(cpu) = options.cpu {
+        set_cpu_affinity(cpu)?;
+        println!("pinned to CPU {cpu}");
+    }
+    if let Some(numa_policy) = &options.numa_policy {
+        println!("NUMA policy for the mmap and memfd modes: {numa_policy}");
+    }
+    #[cfg(target_os = "linux")]
+    println!(
+        "transparent huge pages for this process (prctl): {}",
+        thp_disable_string()?
+    );
+    let fs = KernelFs::default();
+    let page_sizes = PageSizes::read(&fs)?;
+    println!(
+        "base page size: {}; transparent huge page size: {}",
+        page_size_string(page_sizes.base),
+        page_size_string(page_sizes.huge)
+    );
+    let mut harness = Harness {
+        access_options: AccessOptions {
+            seed,
+            precompute_indexes: options.precompute_indexes,
+            record_trace: options.record_trace.clone(),
+            replay_trace: options.replay_trace.clone(),
+        },
+        results: Vec::new(),
+        watch: options.watch,
+        watch_interval: options.watch_interval,
+        watch_timeout: options.watch_timeout,
+        pfn_runs: options.pfn_runs,
+        numa: options.numa,
+        #[cfg(target_os = "linux")]
+        numa_policy: options.numa_policy.clone(),
+        prefault: options.prefault,
+        fs,
+        page_sizes,
+    };
 
-    let mem_before = memory_stats().unwrap();
+    let mem_before = ProcessMemory::read(&harness.fs)?;
     if options.run_mode == RunMode::All || options.run_mode == RunMode::VecOnly {
         let start = Instant::now();
         let mut v = Vec::with_capacity(TEST_SIZE_U64);
@@ -80,128 +338,1168 @@ fn main() -> Result<(), Box<dyn Error>> {
             "Vec: alloc and filled {TEST_SIZE_GIB} GiB in {duration:?}; {}",
             humanunits::byte_rate_string(TEST_SIZE_BYTES, duration)
         );
-        rnd_accesses(&mut rng, &v);
-        let mem_after = memory_stats().unwrap();
-        println!(
-            "RSS before: {}; RSS after: {}; diff: {}\n",
-            humanunits::bytes_string(mem_before.physical_mem),
-            humanunits::bytes_string(mem_after.physical_mem),
-            humanunits::bytes_string(mem_after.physical_mem - mem_before.physical_mem)
-        );
+        // glibc.malloc.hugetlb in GLIBC_TUNABLES can make malloc use huge pages
+        #[cfg(target_os = "linux")]
+        print_thp_coverage(&harness.fs, &v, "AnonHugePages")?;
+        harness.measure("Vec", &v, &mem_before)?;
+        println!();
         drop(v);
     }
 
     if options.run_mode == RunMode::All || options.run_mode == RunMode::MmapOnly {
-        print_hugepage_setting_on_linux()?;
+        print_hugepage_setting_on_linux(&harness.fs)?;
 
-        let mem_before = memory_stats().unwrap();
+        let mem_before = ProcessMemory::read(&harness.fs)?;
         let start = Instant::now();
 
-        let mut v = MmapU64Slice::new_zero(TEST_SIZE_U64)?;
-        for value in v.slice_mut().iter_mut() {
+        let mut v = MmapU64Slice::new_zero_flags(
+            TEST_SIZE_U64,
+            0,
+            options.prefault.map_flags(),
+            page_sizes,
+            options.numa_policy.as_ref(),
+            HugepageAdvice::Hugepage,
+            options.prefault == Prefault::None,
+        )?;
+        harness.run(
+            "MmapSlice",
+            v.slice_mut(),
+            "AnonHugePages",
+            start,
+            &mem_before,
+        )?;
+        drop(v);
+
+        let mem_after_drop = ProcessMemory::read(&harness.fs)?;
+        print_memory_diff("After drop: ", &mem_before, &mem_after_drop);
+    }
+
+    #[cfg(target_os = "linux")]
+    if options.run_mode == RunMode::All || options.run_mode == RunMode::MmapHugeTLB1GiBOnly {
+        run_hugetlb(&mut harness, 1 << 30)?;
+    }
+
+    #[cfg(target_os = "linux")]
+    if options.run_mode == RunMode::MmapHugeTLB {
+        for page_size in hugetlb_page_sizes(&harness.fs, &options.hugetlb_page_size)? {
+            run_hugetlb(&mut harness, page_size)?;
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    if options.run_mode == RunMode::MemfdHugeTLB {
+        for page_size in hugetlb_page_sizes(&harness.fs, &options.hugetlb_page_size)? {
+            run_memfd_hugetlb(&mut harness, page_size)?;
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    if options.run_mode == RunMode::MmapNoHugepage {
+        run_mmap_nohugepage(&mut harness)?;
+    }
+
+    #[cfg(target_os = "linux")]
+    if options.run_mode == RunMode::MmapMisaligned {
+        run_mmap_misaligned(&mut harness, options.misalign_offset)?;
+    }
+
+    #[cfg(target_os = "linux")]
+    if options.run_mode == RunMode::MemfdShmemTHP {
+        run_shmem_thp(&mut harness)?;
+    }
+
+    #[cfg(target_os = "linux")]
+    if options.run_mode == RunMode::VecGlibcTunables {
+        run_vec_glibc_tunables(&mut harness)?;
+    }
+
+    #[cfg(target_os = "linux")]
+    if options.run_mode == RunMode::MmapCollapse {
+        run_collapse(&mut harness)?;
+    }
+
+    #[cfg(target_os = "linux")]
+    if options.run_mode == RunMode::MmapKhugepaged {
+        run_khugepaged(&mut harness)?;
+    }
+
+    #[cfg(target_os = "linux")]
+    if options.run_mode == RunMode::VecMadvise {
+        run_vec_madvise(&mut harness)?;
+    }
+
+    print_results_summary(&harness.results, options.thp_disable);
+    if options.result_lines {
+        for result in &harness.results {
+            println!("{}", result.to_line());
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    print_cpuid_tlb();
+
+    if options.tlb_sim {
+        let predictions =
+            simulate_tlb(&options.tlb_config, TEST_SIZE_U64, &harness.access_options)?;
+        print_tlb_comparison(&harness.results, &predictions);
+    }
+
+    Ok(())
+}
+
+/// State shared by the benchmarks of every run mode.
+struct Harness {
+    access_options: AccessOptions,
+    results: Vec<BenchmarkResult>,
+    watch: bool,
+    watch_interval: Duration,
+    watch_timeout: Duration,
+    pfn_runs: bool,
+    numa: bool,
+    /// Set on each mmap allocation before it is filled. None uses the process policy.
+    #[cfg(target_os = "linux")]
+    numa_policy: Option<NumaPolicy>,
+    /// Applied to slices by [`Harness::run`]. Allocations must use [`Prefault::map_flags`].
+    prefault: Prefault,
+    /// Where the `/proc` and `/sys` files are read from.
+    fs: KernelFs,
+    page_sizes: PageSizes,
+}
+
+impl Harness {
+    /// Prefaults and fills slice, which was allocated at start, then runs the random access
+    /// benchmark and prints the memory used since `mem_before`. The caller must drop the
+    /// allocation. field is the `/proc/self/smaps` field that counts its huge pages.
+    fn run(
+        &mut self,
+        label: &str,
+        slice: &mut [u64],
+        field: &str,
+        start: Instant,
+        mem_before: &ProcessMemory,
+    ) -> Result<(), Box<dyn Error>> {
+        self.fill(label, slice, start)?;
+        self.measure(label, slice, mem_before)?;
+        self.watch_before_drop(label, slice, field)?;
+        Ok(())
+    }
+
+    /// Prefaults and fills slice, which was allocated at start, and prints the time each took.
+    fn fill(&self, label: &str, slice: &mut [u64], start: Instant) -> Result<(), Box<dyn Error>> {
+        let alloc_end = Instant::now();
+        if self.prefault == Prefault::MadvPopulateWrite {
+            madvise_populate_write(slice)?;
+        }
+        let prefault_end = Instant::now();
+        for value in slice.iter_mut() {
             *value = FILLED;
         }
         let end = Instant::now();
         let duration = end - start;
+        let size_bytes = slice.len() * 8;
         println!(
-            "MmapSlice: alloc and filled {TEST_SIZE_GIB} GiB in {duration:?}; {}",
-            humanunits::byte_rate_string(TEST_SIZE_BYTES, duration)
+            "{label}: alloc and filled {} in {duration:?}; {}",
+            humanunits::bytes_string(size_bytes),
+            humanunits::byte_rate_string(size_bytes, duration)
         );
-        let page_size = read_page_size(v.slice().as_ptr() as usize)?;
-        println!("  slice page size = {page_size}");
+        if self.prefault == Prefault::MapPopulate {
+            // mmap populates the mapping before it returns: the prefault is the alloc time
+            println!(
+                "  alloc: included in prefault; prefault={}: {:?}; fill: {:?}",
+                self.prefault,
+                alloc_end - start,
+                end - prefault_end
+            );
+        } else {
+            println!(
+                "  alloc: {:?}; prefault={}: {:?}; fill: {:?}",
+                alloc_end - start,
+                self.prefault,
+                prefault_end - alloc_end,
+                end - prefault_end
+            );
+        }
+        Ok(())
+    }
+
+    /// Runs the random access benchmark on slice, and prints the memory used since `mem_before`.
+    fn measure(
+        &mut self,
+        label: &str,
+        slice: &[u64],
+        mem_before: &ProcessMemory,
+    ) -> Result<(), Box<dyn Error>> {
+        // a Vec is not aligned: check the first address that can be in a huge page
+        let mut page_address =
+            align_pointer_value_up(self.page_sizes.huge, slice.as_ptr() as usize);
+        if page_address >= slice.as_ptr() as usize + slice.len() * 8 {
+            page_address = slice.as_ptr() as usize;
+        }
+        let (page_size, page_size_backend) =
+            read_page_size(&self.fs, self.page_sizes, page_address)?;
+        println!("  slice page size = {page_size} (from {page_size_backend})");
+        if self.pfn_runs {
+            #[cfg(target_os = "linux")]
+            print_pfn_runs(&self.fs, self.page_sizes, slice)?;
+        }
+        if self.numa {
+            #[cfg(target_os = "linux")]
+            print_numa_placement(&self.fs, slice, page_size)?;
+        }
+
+        let access_result = rnd_accesses(slice, &mut self.access_options)?;
+        self.results.push(BenchmarkResult::new(
+            label.to_string(),
+            page_size,
+            page_size_backend,
+            access_result,
+        ));
+        let mem_after = ProcessMemory::read(&self.fs)?;
+        print_memory_diff("", mem_before, &mem_after);
+        Ok(())
+    }
 
-        rnd_accesses(&mut rng, v.slice());
-        let mem_after = memory_stats().unwrap();
+    /// Watches slice with [`Harness::watch_collapse`] if --watch was passed.
+    fn watch_before_drop(
+        &self,
+        label: &str,
+        slice: &[u64],
+        field: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.watch {
+            self.watch_collapse(label, slice, field)?;
+        }
+        Ok(())
+    }
+
+    /// Samples the bytes of slice in huge pages from field in `/proc/self/smaps`, the khugepaged
+    /// counters, and the time for a short random access benchmark every `watch_interval`, until
+    /// the coverage stops changing or `watch_timeout` passes. Prints each sample, then the time
+    /// it took to reach each fraction of the final coverage. Slice must be an entire mapping.
+    fn watch_collapse(
+        &self,
+        label: &str,
+        slice: &[u64],
+        field: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        // about a second with base pages: short enough to sample often
+        const SAMPLE_ACCESSES: usize = 10_000_000;
+        const PERCENTS: [usize; 6] = [10, 25, 50, 75, 90, 100];
+
+        let initial = match KhugepagedStats::read(&self.fs) {
+            Err(hugepagedemo::Error::Io(err)) if err.kind() == std::io::ErrorKind::NotFound => {
+                println!("  {label}: not watching: no khugepaged: {err}");
+                return Ok(());
+            }
+            result => result?,
+        };
+        // khugepaged only runs if transparent huge pages are enabled: full_scans would not change
+        #[cfg(target_os = "linux")]
+        if read_hugepage_setting(&self.fs)? == anyos_hugepages::HugepageSetting::Never {
+            println!(
+                "  {label}: not watching: transparent_hugepage/enabled is never, so khugepaged does not run"
+            );
+            return Ok(());
+        }
+        let start_address = slice.as_ptr() as usize;
+        let size_bytes = slice.len() * 8;
         println!(
-            "RSS before: {}; RSS after: {}; diff: {}",
-            humanunits::bytes_string(mem_before.physical_mem),
-            humanunits::bytes_string(mem_after.physical_mem),
-            humanunits::bytes_string(mem_after.physical_mem - mem_before.physical_mem)
+            "  {label}: watching every {:?}; khugepaged scans {} pages every {:?}: at least {:?} to scan {}; sleeps {:?} after failing to allocate a huge page",
+            self.watch_interval,
+            initial.pages_to_scan,
+            initial.scan_sleep,
+            initial.scan_duration(size_bytes, self.page_sizes.base),
+            humanunits::bytes_string(size_bytes),
+            initial.alloc_sleep,
         );
 
-        if options.sleep_before_drop {
-            const SLEEP_DURATION: Duration = Duration::from_secs(60);
-            println!("sleeping ...");
-            sleep(SLEEP_DURATION);
-            println!("v[0]={}", v.slice()[0]);
+        let mut curve = CollapseCurve::new(size_bytes);
+        let start = Instant::now();
+        let mut next = start;
+        loop {
+            let elapsed = start.elapsed();
+            let mappings = hugepagedemo::read_smaps(&self.fs)?;
+            let huge_bytes = hugepagedemo::sum_smaps_field(
+                &mappings,
+                start_address,
+                start_address + size_bytes,
+                field,
+            );
+            let khugepaged = KhugepagedStats::read(&self.fs)?;
+            let seed = self
+                .access_options
+                .seed
+                .wrapping_add(curve.samples.len() as u64);
+            let mut indexes = RandomIndexes::new(seed, slice.len(), SAMPLE_ACCESSES);
+            let (num_accesses, duration) = timed_accesses(&mut indexes, slice, false)?;
+            let sample = CollapseSample {
+                elapsed,
+                huge_bytes,
+                khugepaged,
+                access_ns: duration.as_nanos() as f64 / num_accesses as f64,
+            };
+            println!(
+                "    {:>8.1?} {field}: {} ({:.1}%); khugepaged full_scans: +{}; pages_collapsed: +{}; access: {:.1} ns",
+                sample.elapsed,
+                humanunits::bytes_string(huge_bytes),
+                huge_bytes as f64 / size_bytes as f64 * 100.0,
+                khugepaged.full_scans - initial.full_scans,
+                khugepaged.pages_collapsed - initial.pages_collapsed,
+                sample.access_ns
+            );
+            curve.push(sample);
+
+            if curve.is_stable() {
+                println!("  {label}: {field} stopped changing after {elapsed:.1?}");
+                break;
+            }
+            if elapsed >= self.watch_timeout {
+                println!(
+                    "  {label}: {field} did not stop changing before --watch-timeout={:?}",
+                    self.watch_timeout
+                );
+                break;
+            }
+            next += self.watch_interval;
+            sleep(next.saturating_duration_since(Instant::now()));
         }
 
-        drop(v);
+        let times = PERCENTS
+            .iter()
+            .map(|&percent| {
+                curve.time_to_percent(percent).map_or_else(
+                    || format!("{percent}%: never"),
+                    |elapsed| format!("{percent}%: {elapsed:.1?}"),
+                )
+            })
+            .collect::<Vec<_>>();
+        println!("  {label}: time to collapse: {}", times.join("; "));
+        Ok(())
+    }
+}
 
-        let mem_after_drop = memory_stats().unwrap();
+/// Returns the hugetlb page sizes to benchmark: the requested sizes, or every size the kernel
+/// supports with enough available pages if none were requested.
+#[cfg(target_os = "linux")]
+fn hugetlb_page_sizes(fs: &KernelFs, requested: &[usize]) -> Result<Vec<usize>, Box<dyn Error>> {
+    let pools = read_hugetlb_pools(fs)?;
+    for pool in &pools {
         println!(
-            "After drop: RSS before: {}; RSS after: {}; diff: {}",
-            humanunits::bytes_string(mem_before.physical_mem),
-            humanunits::bytes_string(mem_after_drop.physical_mem),
-            humanunits::bytes_string(mem_after_drop.physical_mem - mem_before.physical_mem)
+            "hugetlb page size {}: nr_hugepages={} free_hugepages={} resv_hugepages={}",
+            page_size_string(pool.page_size),
+            pool.nr_hugepages,
+            pool.free_hugepages,
+            pool.resv_hugepages
         );
     }
-
-    #[cfg(target_os = "linux")]
-    if options.run_mode == RunMode::All || options.run_mode == RunMode::MmapHugeTLB1GiBOnly {
-        let mem_before = memory_stats().unwrap();
-        let start = Instant::now();
-        let region = match MmapRegion::new_flags(
-            TEST_SIZE_BYTES,
-            MapFlags::MAP_HUGETLB | MapFlags::MAP_HUGE_1GB,
-        ) {
-            Ok(v) => v,
-            Err(nix::Error::ENOMEM) => {
+    if requested.is_empty() {
+        let mut page_sizes = Vec::new();
+        for pool in &pools {
+            if let Some(reason) = pool.unusable_reason(TEST_SIZE_BYTES) {
                 println!(
-                    "ENOMEM: try reserving huge pages with: echo {} | sudo tee /sys/kernel/mm/hugepages/hugepages-1048576kB/nr_hugepages",
-                    TEST_SIZE_U64 * 8 / (1 << 30)
+                    "skipping hugetlb page size {}: {reason}",
+                    page_size_string(pool.page_size)
                 );
-                return Err(Box::from(nix::Error::ENOMEM));
+            } else {
+                page_sizes.push(pool.page_size);
             }
-            Err(err) => {
-                return Err(Box::from(err));
+        }
+        return Ok(page_sizes);
+    }
+    for &page_size in requested {
+        if !pools.iter().any(|pool| pool.page_size == page_size) {
+            return Err(format!(
+                "hugetlb page size {} is not supported by this kernel",
+                page_size_string(page_size)
+            )
+            .into());
+        }
+        if page_size > TEST_SIZE_BYTES {
+            return Err(format!(
+                "hugetlb page size {} is larger than the {TEST_SIZE_GIB} GiB mapping",
+                page_size_string(page_size)
+            )
+            .into());
+        }
+    }
+    Ok(requested.to_vec())
+}
+
+/// Runs the benchmark on a region mapped with `MAP_HUGETLB` and hugetlb pages of `page_size`.
+#[cfg(target_os = "linux")]
+fn run_hugetlb(harness: &mut Harness, page_size: usize) -> Result<(), Box<dyn Error>> {
+    let label = format!("hugetlb {} MmapSlice", page_size_string(page_size));
+    let mem_before = ProcessMemory::read(&harness.fs)?;
+    let start = Instant::now();
+    let region = MmapRegion::new_flags(
+        TEST_SIZE_BYTES,
+        map_huge_flags(page_size) | harness.prefault.map_flags(),
+    )
+    .map_err(|err| hugetlb_mmap_error(err, page_size))?;
+    if let Some(numa_policy) = &harness.numa_policy {
+        region.set_numa_policy(numa_policy)?;
+    }
+    let slice: &mut [u64];
+    unsafe {
+        slice = slice::from_raw_parts_mut(region.get_mut().cast::<u64>(), TEST_SIZE_U64);
+    }
+    harness.run(&label, slice, "Private_Hugetlb", start, &mem_before)?;
+    drop(region);
+
+    let mem_after_drop = ProcessMemory::read(&harness.fs)?;
+    print_memory_diff("After drop: ", &mem_before, &mem_after_drop);
+    Ok(())
+}
+
+/// Runs the benchmark on a `MAP_SHARED` mapping of a memfd backed by hugetlb pages of
+/// `page_size`, which can be shared with other processes by passing the file descriptor.
+#[cfg(target_os = "linux")]
+fn run_memfd_hugetlb(harness: &mut Harness, page_size: usize) -> Result<(), Box<dyn Error>> {
+    let label = format!("hugetlb {} MemfdSlice", page_size_string(page_size));
+    let mem_before = ProcessMemory::read(&harness.fs)?;
+    let start = Instant::now();
+    let fd = memfd_create_sized("hugepagedemo", TEST_SIZE_BYTES, Some(page_size))?;
+    let region = MmapRegion::new_shared_fd(TEST_SIZE_BYTES, &fd, harness.prefault.map_flags())
+        .map_err(|err| hugetlb_mmap_error(err, page_size))?;
+    if let Some(numa_policy) = &harness.numa_policy {
+        region.set_numa_policy(numa_policy)?;
+    }
+    let slice: &mut [u64];
+    unsafe {
+        slice = slice::from_raw_parts_mut(region.get_mut().cast::<u64>(), TEST_SIZE_U64);
+    }
+    harness.run(&label, slice, "Shared_Hugetlb", start, &mem_before)?;
+    drop(region);
+    drop(fd);
+
+    let mem_after_drop = ProcessMemory::read(&harness.fs)?;
+    print_memory_diff("After drop: ", &mem_before, &mem_after_drop);
+    Ok(())
+}
+
+/// Runs the benchmark on the same huge page aligned mapping as `MmapOnly`, but with
+/// `madvise(MADV_NOHUGEPAGE)`. This is a control that only differs in the page size: the `Vec`
+/// mode is not aligned, and can get huge pages if the setting is always.
+#[cfg(target_os = "linux")]
+fn run_mmap_nohugepage(harness: &mut Harness) -> Result<(), Box<dyn Error>> {
+    const LABEL: &str = "MmapNoHugepage";
+
+    print_hugepage_setting_on_linux(&harness.fs)?;
+
+    let mem_before = ProcessMemory::read(&harness.fs)?;
+    let start = Instant::now();
+    let mut v = MmapU64Slice::new_zero_flags(
+        TEST_SIZE_U64,
+        0,
+        harness.prefault.map_flags(),
+        harness.page_sizes,
+        harness.numa_policy.as_ref(),
+        HugepageAdvice::NoHugepage,
+        harness.prefault == Prefault::None,
+    )?;
+    harness.fill(LABEL, v.slice_mut(), start)?;
+
+    let huge_bytes = print_thp_coverage(&harness.fs, v.slice_mut(), "AnonHugePages")?;
+    let slice_start = v.slice_mut().as_ptr() as usize;
+    let nohugepage_flag = hugepagedemo::read_smaps(&harness.fs)?
+        .iter()
+        .any(|mapping| mapping.overlaps(slice_start, slice_start + 1) && mapping.has_vm_flag("nh"));
+    if huge_bytes == 0 && nohugepage_flag {
+        println!("  verified: no transparent huge pages; VmFlags contains nh (MADV_NOHUGEPAGE)");
+    } else {
+        println!(
+            "  WARNING: not a valid control: AnonHugePages={huge_bytes}; VmFlags contains nh? {nohugepage_flag}"
+        );
+    }
+
+    harness.measure(LABEL, v.slice_mut(), &mem_before)?;
+    harness.watch_before_drop(LABEL, v.slice_mut(), "AnonHugePages")?;
+    drop(v);
+
+    let mem_after_drop = ProcessMemory::read(&harness.fs)?;
+    print_memory_diff("After drop: ", &mem_before, &mem_after_drop);
+    Ok(())
+}
+
+/// Runs the benchmark on a huge page aligned `MAP_SHARED` mapping of a regular memfd, after calling
+/// `madvise(MADV_HUGEPAGE)`. Shared memory uses transparent huge pages according to
+/// `shmem_enabled`, not the setting for anonymous memory.
+#[cfg(target_os = "linux")]
+fn run_shmem_thp(harness: &mut Harness) -> Result<(), Box<dyn Error>> {
+    const LABEL: &str = "shmem MemfdSlice";
+
+    print_shmem_hugepage_setting(&harness.fs, harness.page_sizes)?;
+
+    let mem_before = ProcessMemory::read(&harness.fs)?;
+    let start = Instant::now();
+    let fd = memfd_create_sized("hugepagedemo", TEST_SIZE_BYTES, None)?;
+    let region = MmapHugeMadviseAligned::new_shared_fd(
+        harness.page_sizes.huge,
+        TEST_SIZE_BYTES,
+        &fd,
+        harness.prefault.map_flags(),
+    )?;
+    if let Some(numa_policy) = &harness.numa_policy {
+        region.set_numa_policy(numa_policy)?;
+    }
+    let slice: &mut [u64];
+    unsafe {
+        slice = slice::from_raw_parts_mut(region.get_mut().cast::<u64>(), TEST_SIZE_U64);
+    }
+    madvise_hugepages_on_linux(slice, HugepageAdvice::Hugepage, false)?;
+
+    harness.fill(LABEL, slice, start)?;
+    let huge_bytes = print_thp_coverage(&harness.fs, slice, "ShmemPmdMapped")?;
+    if huge_bytes == 0 {
+        println!("  not mapped with shmem huge pages: check shmem_enabled");
+    }
+    harness.measure(LABEL, slice, &mem_before)?;
+    harness.watch_before_drop(LABEL, slice, "ShmemPmdMapped")?;
+    drop(region);
+    drop(fd);
+
+    let mem_after_drop = ProcessMemory::read(&harness.fs)?;
+    print_memory_diff("After drop: ", &mem_before, &mem_after_drop);
+    Ok(())
+}
+
+/// Runs `--run-mode=VecOnly` in a child process with each value of glibc's malloc hugetlb tunable,
+/// and collects its results. Requires glibc 2.35 or later; older versions ignore it. See
+/// <https://www.gnu.org/software/libc/manual/html_node/Memory-Allocation-Tunables.html>.
+#[cfg(target_os = "linux")]
+fn run_vec_glibc_tunables(harness: &mut Harness) -> Result<(), Box<dyn Error>> {
+    // 0: disabled; 1: madvise(MADV_HUGEPAGE) on memory from mmap; 2: use hugetlb pages
+    const TUNABLES: [&str; 3] = [
+        "glibc.malloc.hugetlb=0",
+        "glibc.malloc.hugetlb=1",
+        "glibc.malloc.hugetlb=2",
+    ];
+
+    let exe = std::env::current_exe()?;
+    for tunable in TUNABLES {
+        println!("\nVec with GLIBC_TUNABLES={tunable}:");
+        let mut command = Command::new(&exe);
+        command
+            .arg("--run-mode=VecOnly")
+            .arg(format!("--seed={}", harness.access_options.seed))
+            .arg(format!("--prefault={}", harness.prefault))
+            .arg("--result-lines")
+            .env("GLIBC_TUNABLES", tunable)
+            .stdout(Stdio::piped());
+        if harness.access_options.precompute_indexes {
+            command.arg("--precompute-indexes");
+        }
+        if let Some(path) = &harness.access_options.replay_trace {
+            command.arg("--replay-trace").arg(path);
+        }
+
+        let mut child = command.spawn()?;
+        let stdout = child.stdout.take().expect("BUG: stdout must be piped");
+        for line in BufReader::new(stdout).lines() {
+            let line = line?;
+            if let Some(mut result) = BenchmarkResult::from_line(&line) {
+                result.mode = format!("{} {tunable}", result.mode);
+                harness.results.push(result);
+            } else {
+                println!("  {line}");
             }
-        };
-        let slice: &mut [u64];
-        unsafe {
-            slice = slice::from_raw_parts_mut(region.get_mut().cast::<u64>(), TEST_SIZE_U64);
         }
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(format!("child with GLIBC_TUNABLES={tunable} failed: {status}").into());
+        }
+    }
+    Ok(())
+}
 
-        for value in slice.iter_mut() {
-            *value = FILLED;
+/// Returns the error for a failed hugetlb mapping. For ENOMEM, it first prints how to reserve
+/// hugetlb pages.
+#[cfg(target_os = "linux")]
+fn hugetlb_mmap_error(err: hugepagedemo::Error, page_size: usize) -> Box<dyn Error> {
+    if let hugepagedemo::Error::Os {
+        errno: nix::Error::ENOMEM,
+        ..
+    } = err
+    {
+        println!(
+            "ENOMEM: try reserving huge pages with: echo {} | sudo tee /sys/kernel/mm/hugepages/hugepages-{}kB/nr_hugepages",
+            TEST_SIZE_BYTES.div_ceil(page_size),
+            page_size >> 10
+        );
+    }
+    Box::from(err)
+}
+
+/// Runs the benchmark on a region filled with base pages, then collapses it into transparent huge
+/// pages with `madvise(MADV_COLLAPSE)` and runs the benchmark again.
+#[cfg(target_os = "linux")]
+fn run_collapse(harness: &mut Harness) -> Result<(), Box<dyn Error>> {
+    print_hugepage_setting_on_linux(&harness.fs)?;
+
+    let mem_before = ProcessMemory::read(&harness.fs)?;
+    let start = Instant::now();
+    let mut v = MmapU64Slice::new_zero_flags(
+        TEST_SIZE_U64,
+        0,
+        harness.prefault.map_flags(),
+        harness.page_sizes,
+        harness.numa_policy.as_ref(),
+        HugepageAdvice::NoHugepage,
+        false,
+    )?;
+    harness.fill("MmapCollapse", v.slice_mut(), start)?;
+    let huge_bytes = print_thp_coverage(&harness.fs, v.slice_mut(), "AnonHugePages")?;
+    if huge_bytes != 0 {
+        println!("  WARNING: the baseline before the collapse already has huge pages");
+    }
+    harness.measure("MmapCollapse before collapse", v.slice_mut(), &mem_before)?;
+
+    println!();
+    // MADV_COLLAPSE fails on regions with MADV_NOHUGEPAGE; MADV_HUGEPAGE replaces it
+    madvise_hugepages_on_linux(v.slice_mut(), HugepageAdvice::Hugepage, false)?;
+    let collapse_start = Instant::now();
+    madvise_collapse(v.slice_mut())?;
+    let collapse_duration = collapse_start.elapsed();
+    println!(
+        "MmapCollapse: madvise(MADV_COLLAPSE) of {} took {collapse_duration:?}; {}",
+        humanunits::bytes_string(TEST_SIZE_BYTES),
+        humanunits::byte_rate_string(TEST_SIZE_BYTES, collapse_duration)
+    );
+    print_thp_coverage(&harness.fs, v.slice_mut(), "AnonHugePages")?;
+    harness.measure("MmapCollapse after collapse", v.slice_mut(), &mem_before)?;
+    harness.watch_before_drop("MmapCollapse", v.slice_mut(), "AnonHugePages")?;
+    drop(v);
+
+    let mem_after_drop = ProcessMemory::read(&harness.fs)?;
+    print_memory_diff("After drop: ", &mem_before, &mem_after_drop);
+    Ok(())
+}
+
+/// Runs the benchmark on a region filled with base pages, then calls `madvise(MADV_HUGEPAGE)` and
+/// watches khugepaged collapse it into huge pages in the background, which long-running processes
+/// rely on instead of `MADV_COLLAPSE`. Runs the benchmark again once the coverage stops changing.
+#[cfg(target_os = "linux")]
+fn run_khugepaged(harness: &mut Harness) -> Result<(), Box<dyn Error>> {
+    const LABEL: &str = "MmapKhugepaged";
+
+    print_hugepage_setting_on_linux(&harness.fs)?;
+
+    let mem_before = ProcessMemory::read(&harness.fs)?;
+    let start = Instant::now();
+    // MADV_NOHUGEPAGE ensures the fill only gets base pages, even if the setting is always
+    let mut v = MmapU64Slice::new_zero_flags(
+        TEST_SIZE_U64,
+        0,
+        harness.prefault.map_flags(),
+        harness.page_sizes,
+        harness.numa_policy.as_ref(),
+        HugepageAdvice::NoHugepage,
+        false,
+    )?;
+    harness.fill(LABEL, v.slice_mut(), start)?;
+    print_thp_coverage(&harness.fs, v.slice_mut(), "AnonHugePages")?;
+    harness.measure(
+        "MmapKhugepaged before khugepaged",
+        v.slice_mut(),
+        &mem_before,
+    )?;
+
+    println!();
+    madvise_hugepages_on_linux(v.slice_mut(), HugepageAdvice::Hugepage, false)?;
+    harness.watch_collapse(LABEL, v.slice_mut(), "AnonHugePages")?;
+    print_thp_coverage(&harness.fs, v.slice_mut(), "AnonHugePages")?;
+    harness.measure(
+        "MmapKhugepaged after khugepaged",
+        v.slice_mut(),
+        &mem_before,
+    )?;
+    drop(v);
+
+    let mem_after_drop = ProcessMemory::read(&harness.fs)?;
+    print_memory_diff("After drop: ", &mem_before, &mem_after_drop);
+    Ok(())
+}
+
+/// Runs the benchmark on a Vec, after calling `madvise(MADV_HUGEPAGE)` on the largest huge page
+/// aligned range inside it. The unaligned head and tail of the Vec can only use base pages.
+#[cfg(target_os = "linux")]
+fn run_vec_madvise(harness: &mut Harness) -> Result<(), Box<dyn Error>> {
+    print_hugepage_setting_on_linux(&harness.fs)?;
+
+    let mem_before = ProcessMemory::read(&harness.fs)?;
+    let start = Instant::now();
+    // allocates with calloc, which does not write to new memory from mmap: nothing is faulted in
+    let mut v = vec![0u64; TEST_SIZE_U64];
+    let vec_start = v.as_ptr() as usize;
+    let huge_page_size = harness.page_sizes.huge;
+    let (aligned_start, aligned_end) =
+        huge_aligned_range(huge_page_size, vec_start, TEST_SIZE_BYTES);
+    let head_bytes = aligned_start - vec_start;
+    let tail_bytes = vec_start + TEST_SIZE_BYTES - aligned_end;
+    println!(
+        "Vec returned 0x{vec_start:x}; madvise {} aligned 0x{aligned_start:x}-0x{aligned_end:x}; unaligned head: {}; unaligned tail: {}",
+        page_size_string(huge_page_size),
+        humanunits::bytes_string(head_bytes),
+        humanunits::bytes_string(tail_bytes)
+    );
+    let head_items = head_bytes / 8;
+    let aligned_items = (aligned_end - aligned_start) / 8;
+    madvise_hugepages_on_linux(
+        &mut v[head_items..head_items + aligned_items],
+        HugepageAdvice::Hugepage,
+        false,
+    )?;
+
+    harness.fill("VecMadvise", &mut v, start)?;
+    print_thp_coverage(&harness.fs, &v, "AnonHugePages")?;
+    harness.measure("VecMadvise", &v, &mem_before)?;
+    harness.watch_before_drop("VecMadvise", &v, "AnonHugePages")?;
+    drop(v);
+
+    let mem_after_drop = ProcessMemory::read(&harness.fs)?;
+    print_memory_diff("After drop: ", &mem_before, &mem_after_drop);
+    Ok(())
+}
+
+/// Runs the benchmark on a mapping with `madvise(MADV_HUGEPAGE)` that starts offset bytes past a
+/// huge page boundary. Only the aligned huge pages completely inside it can be huge pages.
+#[cfg(target_os = "linux")]
+fn run_mmap_misaligned(harness: &mut Harness, offset: usize) -> Result<(), Box<dyn Error>> {
+    const LABEL: &str = "MmapMisaligned";
+
+    print_hugepage_setting_on_linux(&harness.fs)?;
+
+    let mem_before = ProcessMemory::read(&harness.fs)?;
+    let start = Instant::now();
+    let mut v = MmapU64Slice::new_zero_flags(
+        TEST_SIZE_U64,
+        offset,
+        harness.prefault.map_flags(),
+        harness.page_sizes,
+        harness.numa_policy.as_ref(),
+        HugepageAdvice::Hugepage,
+        harness.prefault == Prefault::None,
+    )?;
+    harness.fill(LABEL, v.slice_mut(), start)?;
+
+    let huge_page_size = harness.page_sizes.huge;
+    let slice_start = v.slice_mut().as_ptr() as usize;
+    let (aligned_start, aligned_end) =
+        huge_aligned_range(huge_page_size, slice_start, TEST_SIZE_BYTES);
+    let huge_bytes = print_thp_coverage(&harness.fs, v.slice_mut(), "AnonHugePages")?;
+    println!(
+        "  offset {}: full {} pages: {}; huge: {}; base page head: {}; base page tail: {}",
+        humanunits::bytes_string(offset),
+        page_size_string(huge_page_size),
+        (aligned_end - aligned_start) / huge_page_size,
+        huge_bytes / huge_page_size,
+        humanunits::bytes_string(aligned_start - slice_start),
+        humanunits::bytes_string(slice_start + TEST_SIZE_BYTES - aligned_end)
+    );
+
+    harness.measure(LABEL, v.slice_mut(), &mem_before)?;
+    harness.watch_before_drop(LABEL, v.slice_mut(), "AnonHugePages")?;
+    drop(v);
+
+    let mem_after_drop = ProcessMemory::read(&harness.fs)?;
+    print_memory_diff("After drop: ", &mem_before, &mem_after_drop);
+    Ok(())
+}
+
+/// Returns the largest range [start, end) aligned to `huge_page_size` inside the size bytes at
+/// start. Only these addresses can be mapped with huge pages of that size.
+#[cfg(target_os = "linux")]
+fn huge_aligned_range(huge_page_size: usize, start: usize, size: usize) -> (usize, usize) {
+    let aligned_start = align_pointer_value_up(huge_page_size, start);
+    let aligned_end = align_pointer_value_down(huge_page_size, start + size);
+    (aligned_start, aligned_end.max(aligned_start))
+}
+
+/// Prints how much of slice is mapped with transparent huge pages, from field in
+/// `/proc/self/smaps`: `AnonHugePages` for anonymous memory, or `ShmemPmdMapped` for shared
+/// memory. Slice must be an entire mapping. Returns the bytes in huge pages.
+#[cfg(target_os = "linux")]
+fn print_thp_coverage(fs: &KernelFs, slice: &[u64], field: &str) -> Result<usize, Box<dyn Error>> {
+    let start = slice.as_ptr() as usize;
+    let size_bytes = slice.len() * 8;
+    let mappings = hugepagedemo::read_smaps(fs)?;
+    let huge_bytes = hugepagedemo::sum_smaps_field(&mappings, start, start + size_bytes, field);
+    println!(
+        "  {field}: {} of {} ({:.1}%); not in huge pages: {}",
+        humanunits::bytes_string(huge_bytes),
+        humanunits::bytes_string(size_bytes),
+        huge_bytes as f64 / size_bytes as f64 * 100.0,
+        signed_bytes_string(huge_bytes, size_bytes)
+    );
+    Ok(huge_bytes)
+}
+
+/// Prints how physically contiguous slice is, from the page frame numbers in
+/// `/proc/self/pagemap`. Prints a message and returns Ok if this process cannot see them.
+#[cfg(target_os = "linux")]
+fn print_pfn_runs(
+    fs: &KernelFs,
+    page_sizes: PageSizes,
+    slice: &[u64],
+) -> Result<(), Box<dyn Error>> {
+    let start = slice.as_ptr() as usize;
+    let size_bytes = slice.len() * 8;
+    let pfns = match read_pagemap_pfns(fs, page_sizes, start, size_bytes) {
+        Err(err @ hugepagedemo::Error::PermissionDenied { .. }) => {
+            println!("  physical contiguity: {err}");
+            return Ok(());
         }
-        let end = Instant::now();
-        let duration = end - start;
+        result => result?,
+    };
+    let runs = PfnRuns::new(
+        &pfns,
+        (start / page_sizes.base) as u64,
+        page_sizes.huge / page_sizes.base,
+    );
+
+    let huge_string = page_size_string(page_sizes.huge);
+    println!(
+        "  physical contiguity: {} pages present ({} not present) in {} runs; {} distinct {huge_string} physical blocks",
+        runs.present_pages,
+        runs.not_present_pages,
+        runs.run_lengths.len(),
+        runs.physical_blocks
+    );
+    for (bucket_min, run_count, pages) in runs.histogram() {
         println!(
-            "hugetlb 1GiB MmapSlice: alloc and filled {TEST_SIZE_GIB} GiB in {duration:?}; {}",
-            humanunits::byte_rate_string(TEST_SIZE_BYTES, duration)
+            "    runs of {bucket_min}-{} pages: {run_count} runs; {pages} pages ({:.1}%)",
+            bucket_min * 2 - 1,
+            pages as f64 / runs.present_pages as f64 * 100.0
         );
-        let page_size = read_page_size(region.get_mut() as usize)?;
-        println!("  slice page size = {page_size}");
+    }
+    let (aligned_start, aligned_end) = huge_aligned_range(page_sizes.huge, start, size_bytes);
+    println!(
+        "  {huge_string} aligned ranges physically contiguous: {} of {}; naturally aligned in physical memory: {}",
+        runs.contiguous_huge_ranges,
+        (aligned_end - aligned_start) / page_sizes.huge,
+        runs.aligned_huge_ranges
+    );
+    Ok(())
+}
+
+/// Prints the NUMA node of every page of slice, which has pages of `page_size`, and the counts
+/// from the mappings that contain it in `/proc/self/numa_maps`. Remote pages are slower to
+/// access, which can hide or exaggerate the difference made by huge pages.
+#[cfg(target_os = "linux")]
+fn print_numa_placement(
+    fs: &KernelFs,
+    slice: &[u64],
+    page_size: usize,
+) -> Result<(), Box<dyn Error>> {
+    let start = slice.as_ptr() as usize;
+    let end = start + slice.len() * 8;
+
+    // a Vec is not aligned: the first page may start before the slice
+    let addresses = (align_pointer_value_down(page_size, start)..end)
+        .step_by(page_size)
+        .map(|address| address.max(start))
+        .collect::<Vec<_>>();
+    match hugepagedemo::query_page_nodes(&addresses) {
+        Err(err) if hugepagedemo::is_numa_unsupported(&err) => {
+            println!("  move_pages: not supported: {err}");
+        }
+        result => {
+            let mut node_pages = std::collections::BTreeMap::<u32, usize>::new();
+            let mut not_present = 0;
+            for node in result? {
+                match node {
+                    Ok(node) => *node_pages.entry(node).or_default() += 1,
+                    Err(_) => not_present += 1,
+                }
+            }
+            let node_strings = node_pages
+                .iter()
+                .map(|(node, pages)| {
+                    format!(
+                        "N{node}={pages} ({:.1}%)",
+                        *pages as f64 / addresses.len() as f64 * 100.0
+                    )
+                })
+                .collect::<Vec<_>>();
+            println!(
+                "  move_pages: {} pages of {}: {}; not present: {not_present}",
+                addresses.len(),
+                page_size_string(page_size),
+                node_strings.join(" ")
+            );
+        }
+    }
 
-        rnd_accesses(&mut rng, slice);
-        let mem_after = memory_stats().unwrap();
+    let numa_maps = match hugepagedemo::read_numa_maps(fs) {
+        Err(hugepagedemo::Error::Io(err)) if err.kind() == std::io::ErrorKind::NotFound => {
+            println!("  numa_maps: not supported by this kernel (requires CONFIG_NUMA)");
+            return Ok(());
+        }
+        result => result?,
+    };
+    // numa_maps only lists the start address of each mapping, in address order
+    let first = numa_maps
+        .iter()
+        .rposition(|mapping| mapping.start <= start)
+        .unwrap_or(0);
+    for mapping in numa_maps[first..]
+        .iter()
+        .take_while(|mapping| mapping.start < end)
+    {
+        let node_strings = mapping
+            .node_pages
+            .iter()
+            .map(|(node, pages)| format!("N{node}={pages}"))
+            .collect::<Vec<_>>();
         println!(
-            "RSS before: {}; RSS after: {}; diff: {}",
-            humanunits::bytes_string(mem_before.physical_mem),
-            humanunits::bytes_string(mem_after.physical_mem),
-            humanunits::bytes_string(mem_after.physical_mem - mem_before.physical_mem)
+            "  numa_maps 0x{:x}: policy {}; huge={}; kernelpagesize_kB={}; {}",
+            mapping.start,
+            mapping.policy,
+            mapping.huge,
+            mapping.kernel_page_size.unwrap_or(0) >> 10,
+            node_strings.join(" ")
         );
+    }
+    Ok(())
+}
+
+/// The measured result of one random access benchmark.
+struct AccessResult {
+    num_accesses: usize,
+    duration: Duration,
+    /// Time per access with the calibration overhead subtracted.
+    net_ns: f64,
+}
 
-        if options.sleep_before_drop {
-            const SLEEP_DURATION: Duration = Duration::from_secs(60);
-            println!("sleeping ...");
-            sleep(SLEEP_DURATION);
-            println!("v[0]={}", slice[0]);
+/// The random access result of one run mode, with the page size it used.
+struct BenchmarkResult {
+    mode: String,
+    page_size: usize,
+    page_size_backend: PageSizeBackend,
+    access: AccessResult,
+}
+
+impl BenchmarkResult {
+    const LINE_PREFIX: &str = "RESULT\t";
+
+    const fn new(
+        mode: String,
+        page_size: usize,
+        page_size_backend: PageSizeBackend,
+        access: AccessResult,
+    ) -> Self {
+        Self {
+            mode,
+            page_size,
+            page_size_backend,
+            access,
         }
+    }
 
-        drop(region);
+    /// Returns a tab separated line that can be parsed by [`BenchmarkResult::from_line`].
+    fn to_line(&self) -> String {
+        format!(
+            "{}{}\t{}\t{}\t{}\t{}\t{}",
+            Self::LINE_PREFIX,
+            self.mode,
+            self.page_size,
+            self.page_size_backend,
+            self.access.num_accesses,
+            self.access.duration.as_nanos(),
+            self.access.net_ns
+        )
+    }
 
-        let mem_after_drop = memory_stats().unwrap();
+    /// Parses a line written by [`BenchmarkResult::to_line`]. Returns None for any other line.
+    #[cfg(any(test, target_os = "linux"))]
+    fn from_line(line: &str) -> Option<Self> {
+        let fields = line.strip_prefix(Self::LINE_PREFIX)?;
+        let [
+            mode,
+            page_size,
+            page_size_backend,
+            num_accesses,
+            duration_ns,
+            net_ns,
+        ] = fields.split('\t').collect::<Vec<_>>()[..]
+        else {
+            return None;
+        };
+        Some(Self::new(
+            mode.to_string(),
+            page_size.parse().ok()?,
+            page_size_backend.parse().ok()?,
+            AccessResult {
+                num_accesses: num_accesses.parse().ok()?,
+                duration: Duration::from_nanos(duration_ns.parse().ok()?),
+                net_ns: net_ns.parse().ok()?,
+            },
+        ))
+    }
+}
+
+/// Prints the result of every benchmark, to compare runs with different options.
+fn print_results_summary(results: &[BenchmarkResult], thp_disable: ThpDisable) {
+    println!("\nresults with --thp-disable={thp_disable}:");
+    for result in results {
         println!(
-            "After drop: RSS before: {}; RSS after: {}; diff: {}",
-            humanunits::bytes_string(mem_before.physical_mem),
-            humanunits::bytes_string(mem_after_drop.physical_mem),
-            humanunits::bytes_string(mem_after_drop.physical_mem - mem_before.physical_mem)
+            "  {}: page size {} (from {}); net {:.2} ns/access; {:.1} accesses/sec",
+            result.mode,
+            page_size_string(result.page_size),
+            result.page_size_backend,
+            result.access.net_ns,
+            result.access.num_accesses as f64 / result.access.duration.as_secs_f64(),
         );
     }
+}
 
-    Ok(())
+/// Prints the data TLB geometry reported by CPUID, and how much memory it can translate.
+#[cfg(target_arch = "x86_64")]
+fn print_cpuid_tlb() {
+    const PAGE_SIZES: [usize; 3] = [4 << 10, 2 << 20, 1 << 30];
+
+    let Some(tlb) = hugepagedemo::read_cpuid_tlb() else {
+        println!("\nCPUID does not describe the data TLB");
+        return;
+    };
+    println!(
+        "\nCPUID data TLB from {}: --tlb-config={}",
+        tlb.source, tlb.config
+    );
+    for page_size in PAGE_SIZES {
+        let reach_strings = tlb
+            .config
+            .reach(page_size)
+            .into_iter()
+            .enumerate()
+            .map(|(level, reach)| format!("L{} {}", level + 1, humanunits::bytes_string(reach)))
+            .collect::<Vec<_>>();
+        println!(
+            "  TLB reach with {} pages: {}",
+            page_size_string(page_size),
+            reach_strings.join("; ")
+        );
+    }
+}
+
+/// Replays the benchmark's index stream through a simulated TLB for each page size it supports,
+/// and the base page size. Returns the predicted hits and misses for each page size.
+fn simulate_tlb(
+    config: &TlbConfig,
+    data_len: usize,
+    access_options: &AccessOptions,
+) -> Result<Vec<(usize, TlbStats)>, Box<dyn Error>> {
+    // a prefix of the benchmark's accesses: enough for stable rates, without taking too long
+    const TLB_SIM_ACCESSES: usize = 20_000_000;
+
+    let mut page_sizes = config.page_sizes();
+    page_sizes.push(hugepagedemo::sysconf_page_size());
+    page_sizes.sort_unstable();
+    page_sizes.dedup();
+
+    println!("\nTLB simulation: {config}");
+    let mut predictions = Vec::new();
+    for page_size in page_sizes {
+        let mut indexes: Box<dyn IndexSource> = match &access_options.replay_trace {
+            Some(path) => Box::new(AccessTraceReader::new(BufReader::new(File::open(path)?))?),
+            None => Box::new(RandomIndexes::new(
+                access_options.seed,
+                data_len,
+                TLB_SIM_ACCESSES,
+            )),
+        };
+
+        let mut sim = TlbSimulator::new(config, page_size);
+        let mut index_buffer = vec![0; 1 << 16];
+        let mut remaining = TLB_SIM_ACCESSES;
+        while remaining > 0 {
+            let count = indexes.next_indexes(&mut index_buffer[..remaining.min(1 << 16)])?;
+            if count == 0 {
+                break;
+            }
+            for &index in &index_buffer[..count] {
+                sim.access(index * 8);
+            }
+            remaining -= count;
+        }
+
+        let stats = sim.stats().clone();
+        let level_strings = (0..stats.level_hits.len())
+            .zip(config.reach(page_size))
+            .map(|(level, reach)| {
+                format!(
+                    "L{} hit {:.2}% (reach {})",
+                    level + 1,
+                    stats.level_hit_rate(level) * 100.0,
+                    humanunits::bytes_string(reach)
+                )
+            })
+            .collect::<Vec<_>>();
+        println!(
+            "  page size {}: {}; page walks {:.2}% of {} accesses",
+            page_size_string(page_size),
+            level_strings.join("; "),
+            stats.miss_rate() * 100.0,
+            stats.accesses
+        );
+        predictions.push((page_size, stats));
+    }
+    Ok(predictions)
+}
+
+/// Prints the measured results next to the TLB simulator's predictions for the same page size.
+fn print_tlb_comparison(results: &[BenchmarkResult], predictions: &[(usize, TlbStats)]) {
+    let Some(baseline) = results.first() else {
+        return;
+    };
+    println!(
+        "measured vs predicted (speedup relative to {}):",
+        baseline.mode
+    );
+    for result in results {
+        // net_ns is clamped to 0 when the access is no slower than the calibration overhead
+        let speedup = if result.access.net_ns > 0.0 {
+            format!("{:.2}x", baseline.access.net_ns / result.access.net_ns)
+        } else {
+            "unknown".to_string()
+        };
+        let predicted_walks = predictions
+            .iter()
+            .find(|(page_size, _)| *page_size == result.page_size)
+            .map_or_else(
+                || "no prediction".to_string(),
+                |(_, stats)| format!("{:.2}%", stats.miss_rate() * 100.0),
+            );
+        println!(
+            "  {} page size {}: net {:.2} ns/access; {:.1} accesses/sec; speedup {speedup}; predicted page walks {predicted_walks}",
+            result.mode,
+            page_size_string(result.page_size),
+            result.access.net_ns,
+            result.access.num_accesses as f64 / result.access.duration.as_secs_f64(),
+        );
+    }
+}
+
+/// Prints each field of the process memory usage before and after, and the difference.
+fn print_memory_diff(prefix: &str, before: &ProcessMemory, after: &ProcessMemory) {
+    for ((label, before_bytes), (_, after_bytes)) in before.fields().into_iter().zip(after.fields())
+    {
+        println!(
+            "{prefix}{label} before: {}; {label} after: {}; diff: {}",
+            humanunits::bytes_string(before_bytes),
+            humanunits::bytes_string(after_bytes),
+            signed_bytes_string(before_bytes, after_bytes)
+        );
+    }
+}
+
+/// Returns a human readable string for after - before, which may be negative.
+fn signed_bytes_string(before: usize, after: usize) -> String {
+    if after >= before {
+        humanunits::bytes_string(after - before)
+    } else {
+        format!("-{}", humanunits::bytes_string(before - after))
+    }
 }
 
 /// Allocates a memory region with mmap that is aligned with a specific alignment. This can be used
@@ -215,20 +1513,26 @@ impl MmapHugeMadviseAligned {
     // argument order is the same as aligned_alloc.
     #[cfg(test)]
     fn new(alignment: usize, size: usize) -> Result<Self, nix::errno::Errno> {
-        Self::new_flags(alignment, size, MapFlags::empty())
+        Self::new_offset_flags(alignment, 0, size, MapFlags::empty())
     }
 
-    // argument order is the same as aligned_alloc.
-    fn new_flags(
+    /// Allocates a region that starts offset bytes past an aligned address, to test misaligned
+    /// mappings. offset must be a multiple of the page size, and less than alignment.
+    fn new_offset_flags(
         alignment: usize,
+        offset: usize,
         size: usize,
         flags: MapFlags,
     ) -> Result<Self, nix::errno::Errno> {
+        if offset >= alignment || !offset.is_multiple_of(hugepagedemo::sysconf_page_size()) {
+            return Err(nix::errno::Errno::EINVAL);
+        }
+
         // worse case alignment: mmap returns 1 byte off the alignment, we must waste alignment-1 bytes.
-        // To ensure we can do this, we request size+alignment bytes.
+        // To ensure we can do this, we request size+offset+alignment bytes.
         // This shouldn't be so bad: untouched pages won't actually be allocated.
-        let align_rounded_size =
-            NonZeroUsize::new(size + alignment).expect("BUG: alignment and size must be > 0");
+        let align_rounded_size = NonZeroUsize::new(size + offset + alignment)
+            .expect("BUG: alignment and size must be > 0");
 
         let mmap_pointer: NonNull<c_void>;
         unsafe {
@@ -246,7 +1550,8 @@ impl MmapHugeMadviseAligned {
         // allow the kernel to coalesce them into huge pages? Not sure.
         let mmap_pointer_usize = mmap_pointer.as_ptr() as usize;
         let allocation_end = mmap_pointer_usize + align_rounded_size.get();
-        let aligned_pointer_usize = align_pointer_value_down(alignment, allocation_end - size);
+        let aligned_pointer_usize =
+            align_pointer_value_down(alignment, allocation_end - size - offset) + offset;
         // alternative of taking the lowest aligned address
         // let aligned_pointer =
         //     align_pointer_value_up(alignment, mmap_pointer as usize) as *mut c_void;
@@ -295,12 +1600,48 @@ impl MmapHugeMadviseAligned {
         })
     }
 
+    /// Maps size bytes of the file fd with `MAP_SHARED` at an address aligned to alignment. This
+    /// reserves an aligned anonymous region, then replaces it with the file using `MAP_FIXED`.
+    #[cfg(target_os = "linux")]
+    fn new_shared_fd<Fd: AsFd>(
+        alignment: usize,
+        size: usize,
+        fd: Fd,
+        flags: MapFlags,
+    ) -> Result<Self, nix::errno::Errno> {
+        let reserved = Self::new_offset_flags(alignment, 0, size, MapFlags::empty())?;
+        let aligned_address = NonZeroUsize::new(reserved.get_mut() as usize).unwrap();
+        unsafe {
+            nix::sys::mman::mmap(
+                Some(aligned_address),
+                NonZeroUsize::new(size).expect("BUG: size must be > 0"),
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_SHARED | MapFlags::MAP_FIXED | flags,
+                fd,
+                0,
+            )?;
+        }
+        Ok(reserved)
+    }
+
     const fn get_mut(&self) -> *mut c_void {
         self.region.get_mut()
     }
+
+    /// Sets the NUMA memory policy for the region. See [`MmapOwner::set_numa_policy`].
+    #[cfg(target_os = "linux")]
+    fn set_numa_policy(&self, policy: &NumaPolicy) -> Result<(), hugepagedemo::Error> {
+        self.region.set_numa_policy(policy)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    #[allow(clippy::unnecessary_wraps, clippy::unused_self)]
+    fn set_numa_policy(&self, _policy: &NumaPolicy) -> Result<(), hugepagedemo::Error> {
+        println!("not running on linux; not calling mbind");
+        Ok(())
+    }
 }
 
-#[cfg(test)]
 fn align_pointer_value_up(alignment: usize, pointer_value: usize) -> usize {
     // see bit hacks to check if power of two:
     // https://graphics.stanford.edu/~seander/bithacks.html#DetermineIfPowerOf2
@@ -326,18 +1667,31 @@ struct MmapU64Slice<'a> {
 }
 
 impl MmapU64Slice<'_> {
-    fn new_zero(items: usize) -> Result<Self, nix::errno::Errno> {
-        Self::new_zero_flags(items, MapFlags::empty())
-    }
-
-    fn new_zero_flags(items: usize, flags: MapFlags) -> Result<Self, nix::errno::Errno> {
-        const HUGE_2MIB_ALIGNMENT: usize = 2 << 20;
-        const HUGE_2MIB_MASK: usize = HUGE_2MIB_ALIGNMENT - 1;
-        const HUGE_1GIB_ALIGNMENT: usize = 1 << 30;
-        const HUGE_1GIB_MASK: usize = HUGE_1GIB_ALIGNMENT - 1;
+    /// Allocates a slice that starts offset bytes past a transparent huge page aligned address,
+    /// sets `numa_policy` if it is not None, and calls madvise with advice. If `touch` is true, it
+    /// then writes to every page to fault it in.
+    fn new_zero_flags(
+        items: usize,
+        offset: usize,
+        flags: MapFlags,
+        page_sizes: PageSizes,
+        numa_policy: Option<&NumaPolicy>,
+        advice: HugepageAdvice,
+        touch: bool,
+    ) -> Result<Self, hugepagedemo::Error> {
+        let huge_mask = page_sizes.huge - 1;
+        let pud_mask = page_sizes.pud() - 1;
 
         let mem_size = items * 8;
-        let allocation = MmapHugeMadviseAligned::new_flags(HUGE_2MIB_ALIGNMENT, mem_size, flags)?;
+        let allocation =
+            MmapHugeMadviseAligned::new_offset_flags(page_sizes.huge, offset, mem_size, flags)
+                .map_err(|errno| hugepagedemo::Error::Os {
+                    call: "mmap",
+                    errno,
+                })?;
+        if let Some(numa_policy) = numa_policy {
+            allocation.set_numa_policy(numa_policy)?;
+        }
         let slice_pointer = allocation.get_mut();
         let slice: &mut [u64];
         unsafe {
@@ -348,22 +1702,20 @@ impl MmapU64Slice<'_> {
             _allocation: allocation,
             slice,
         };
-        madvise_hugepages_on_linux(m.slice);
+        madvise_hugepages_on_linux(m.slice, advice, touch)?;
 
         let (mmap_pointer, _) = m.mmap_parts();
         let ptr_usize = mmap_pointer as usize;
         println!(
-            "mmap aligned returned {mmap_pointer:x?}; aligned to 2MiB (0x{HUGE_2MIB_MASK:x})? {}; aligned to 1GiB (0x{HUGE_1GIB_MASK:x})? {}",
-            ptr_usize & HUGE_2MIB_MASK == 0,
-            ptr_usize & HUGE_1GIB_MASK == 0
+            "mmap aligned returned {mmap_pointer:x?}; aligned to {} (0x{huge_mask:x})? {}; aligned to {} (0x{pud_mask:x})? {}",
+            page_size_string(page_sizes.huge),
+            ptr_usize & huge_mask == 0,
+            page_size_string(page_sizes.pud()),
+            ptr_usize & pud_mask == 0
         );
         Ok(m)
     }
 
-    const fn slice(&self) -> &[u64] {
-        self.slice
-    }
-
     const fn slice_mut(&mut self) -> &mut [u64] {
         self.slice
     }
@@ -375,22 +1727,194 @@ impl MmapU64Slice<'_> {
     }
 }
 
-fn rnd_accesses(rng: &mut dyn RngCore, data: &[u64]) {
+/// Controls how the random access benchmark chooses indexes.
+struct AccessOptions {
+    seed: u64,
+    precompute_indexes: bool,
+    /// Recorded by the next benchmark, then cleared.
+    record_trace: Option<PathBuf>,
+    replay_trace: Option<PathBuf>,
+}
+
+/// Runs the random access benchmark on data and prints the results. The cost of generating random
+/// indexes and checking the values is measured with a calibration pass over a small array that
+/// stays in the L1 cache, and is subtracted to report the "net" cost of each memory access.
+fn rnd_accesses(data: &[u64], options: &mut AccessOptions) -> Result<AccessResult, Box<dyn Error>> {
     const NUM_ACCESSES: usize = 200_000_000;
+    // 4 KiB: fits in the L1 cache and a single base page
+    const CALIBRATION_LEN: usize = 512;
+    const CALIBRATION_ACCESSES: usize = NUM_ACCESSES / 10;
 
-    let index_distribution = Uniform::new(0, data.len()).unwrap();
+    // reading or writing traces must be done outside the timed loop
+    let precompute_indexes = options.precompute_indexes
+        || options.record_trace.is_some()
+        || options.replay_trace.is_some();
+
+    let calibration_data = [FILLED; CALIBRATION_LEN];
+    let mut calibration_indexes =
+        RandomIndexes::new(options.seed, CALIBRATION_LEN, CALIBRATION_ACCESSES);
+    let (calibration_accesses, calibration_duration) = timed_accesses(
+        &mut calibration_indexes,
+        &calibration_data,
+        precompute_indexes,
+    )?;
+    let overhead_ns = calibration_duration.as_nanos() as f64 / calibration_accesses as f64;
+
+    let (num_accesses, duration) = if let Some(path) = &options.replay_trace {
+        let mut trace = AccessTraceReader::new(BufReader::new(File::open(path)?))?;
+        if trace.data_len() > data.len() {
+            return Err(format!(
+                "trace {} was recorded from {} elements; only have {}",
+                path.display(),
+                trace.data_len(),
+                data.len()
+            )
+            .into());
+        }
+        timed_precomputed_accesses(&mut trace, data)?
+    } else {
+        let mut indexes = RandomIndexes::new(options.seed, data.len(), NUM_ACCESSES);
+        if let Some(path) = options.record_trace.take() {
+            let trace = AccessTraceWriter::new(BufWriter::new(File::create(&path)?), data.len())?;
+            let mut recording = RecordingIndexes {
+                source: indexes,
+                trace,
+            };
+            let result = timed_precomputed_accesses(&mut recording, data)?;
+            recording.trace.finish()?;
+            println!("recorded access trace to {}", path.display());
+            result
+        } else {
+            timed_accesses(&mut indexes, data, precompute_indexes)?
+        }
+    };
+
+    let per_access_ns = duration.as_nanos() as f64 / num_accesses as f64;
+    println!(
+        "{num_accesses} accesses in {duration:?}; {:.1} accesses/sec",
+        num_accesses as f64 / duration.as_secs_f64()
+    );
+    let net_ns = (per_access_ns - overhead_ns).max(0.0);
+    println!(
+        "  per access: {per_access_ns:.2} ns; calibration overhead: {overhead_ns:.2} ns; net: {net_ns:.2} ns (precompute_indexes={precompute_indexes})"
+    );
+    Ok(AccessResult {
+        num_accesses,
+        duration,
+        net_ns,
+    })
+}
+
+/// A source of indexes for the random access benchmark.
+trait IndexSource {
+    /// Fills indexes with the next indexes to access. Returns the number of indexes, which is less
+    /// than `indexes.len()` only when there are no more indexes.
+    fn next_indexes(&mut self, indexes: &mut [usize]) -> Result<usize, hugepagedemo::Error>;
+}
+
+/// Generates a fixed number of uniformly distributed random indexes from a seed.
+struct RandomIndexes {
+    // the rand book suggests SmallRng is fast and pretty good:
+    // https://rust-random.github.io/book/guide-rngs.html
+    rng: SmallRng,
+    distribution: Uniform<usize>,
+    remaining: usize,
+}
+
+impl RandomIndexes {
+    fn new(seed: u64, data_len: usize, num_accesses: usize) -> Self {
+        Self {
+            rng: SmallRng::seed_from_u64(seed),
+            distribution: Uniform::new(0, data_len).unwrap(),
+            remaining: num_accesses,
+        }
+    }
+}
+
+impl IndexSource for RandomIndexes {
+    fn next_indexes(&mut self, indexes: &mut [usize]) -> Result<usize, hugepagedemo::Error> {
+        let count = indexes.len().min(self.remaining);
+        for index in &mut indexes[..count] {
+            *index = self.distribution.sample(&mut self.rng);
+        }
+        self.remaining -= count;
+        Ok(count)
+    }
+}
+
+impl<R: std::io::Read> IndexSource for AccessTraceReader<R> {
+    fn next_indexes(&mut self, indexes: &mut [usize]) -> Result<usize, hugepagedemo::Error> {
+        self.read_indexes(indexes)
+    }
+}
+
+/// Writes the indexes returned by source to a trace.
+struct RecordingIndexes<S: IndexSource, W: std::io::Write> {
+    source: S,
+    trace: AccessTraceWriter<W>,
+}
+
+impl<S: IndexSource, W: std::io::Write> IndexSource for RecordingIndexes<S, W> {
+    fn next_indexes(&mut self, indexes: &mut [usize]) -> Result<usize, hugepagedemo::Error> {
+        let count = self.source.next_indexes(indexes)?;
+        self.trace.write_indexes(&indexes[..count])?;
+        Ok(count)
+    }
+}
+
+/// Reads the random elements of data chosen by indexes, and returns the number of accesses and
+/// the time it took. If `precompute_indexes` is true, the random indexes are generated into a
+/// buffer before starting the timer, so the timed loop only contains the loads.
+fn timed_accesses(
+    indexes: &mut RandomIndexes,
+    data: &[u64],
+    precompute_indexes: bool,
+) -> Result<(usize, Duration), hugepagedemo::Error> {
+    if precompute_indexes {
+        return timed_precomputed_accesses(indexes, data);
+    }
+
+    let num_accesses = indexes.remaining;
     let start = Instant::now();
-    for _ in 0..NUM_ACCESSES {
-        let index = index_distribution.sample(rng);
+    for _ in 0..num_accesses {
+        let index = indexes.distribution.sample(&mut indexes.rng);
         let v = data[index];
         assert_eq!(v, FILLED);
     }
-    let end = Instant::now();
-    let duration = end - start;
-    println!(
-        "{NUM_ACCESSES} accesses in {duration:?}; {:.1} accesses/sec",
-        NUM_ACCESSES as f64 / duration.as_secs_f64()
-    );
+    let duration = start.elapsed();
+    indexes.remaining = 0;
+    Ok((num_accesses, duration))
+}
+
+/// Reads the elements of data chosen by indexes, which are read into a buffer before starting the
+/// timer. Returns the number of accesses and the time spent on the loads.
+fn timed_precomputed_accesses(
+    indexes: &mut dyn IndexSource,
+    data: &[u64],
+) -> Result<(usize, Duration), hugepagedemo::Error> {
+    // 512 KiB of indexes: small enough to stay in the L2 cache
+    const INDEX_BUFFER_LEN: usize = 1 << 16;
+
+    let mut index_buffer = vec![0; INDEX_BUFFER_LEN];
+    let mut duration = Duration::ZERO;
+    let mut num_accesses = 0;
+    let mut sum = 0u64;
+    loop {
+        let count = indexes.next_indexes(&mut index_buffer)?;
+        if count == 0 {
+            break;
+        }
+
+        let start = Instant::now();
+        for &index in &index_buffer[..count] {
+            sum = sum.wrapping_add(data[index]);
+        }
+        duration += start.elapsed();
+        num_accesses += count;
+    }
+    // check the values after the timed loop; also ensures the loads are not optimized away
+    assert_eq!(sum, FILLED.wrapping_mul(num_accesses as u64));
+    Ok((num_accesses, duration))
 }
 
 #[cfg(test)]
@@ -431,6 +1955,69 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_timed_accesses() {
+        let data = [FILLED; 1000];
+        // the non-precomputed loop checks every value; the precomputed loop checks the sum
+        let mut indexes = RandomIndexes::new(1, data.len(), 1000);
+        assert_eq!(1000, timed_accesses(&mut indexes, &data, false).unwrap().0);
+        let mut indexes = RandomIndexes::new(1, data.len(), (1 << 16) + 1);
+        assert_eq!(
+            (1 << 16) + 1,
+            timed_accesses(&mut indexes, &data, true).unwrap().0
+        );
+    }
+
+    #[test]
+    fn test_result_line() {
+        let result = BenchmarkResult::new(
+            "Vec".to_string(),
+            4096,
+            PageSizeBackend::Smaps,
+            AccessResult {
+                num_accesses: 200_000_000,
+                duration: Duration::from_nanos(6_421_793_881),
+                net_ns: 27.125,
+            },
+        );
+        let parsed = BenchmarkResult::from_line(&result.to_line()).unwrap();
+        assert_eq!("Vec", parsed.mode);
+        assert_eq!(4096, parsed.page_size);
+        assert_eq!(PageSizeBackend::Smaps, parsed.page_size_backend);
+        assert_eq!(200_000_000, parsed.access.num_accesses);
+        assert_eq!(result.access.duration, parsed.access.duration);
+        assert!((parsed.access.net_ns - 27.125).abs() < f64::EPSILON);
+
+        assert!(BenchmarkResult::from_line("  Vec: page size 4K").is_none());
+        assert!(BenchmarkResult::from_line("RESULT\tVec\t4096").is_none());
+    }
+
+    #[test]
+    fn test_record_replay_indexes() {
+        const DATA_LEN: usize = 1000;
+        const NUM_ACCESSES: usize = 100;
+
+        let trace = AccessTraceWriter::new(Vec::new(), DATA_LEN).unwrap();
+        let mut recording = RecordingIndexes {
+            source: RandomIndexes::new(42, DATA_LEN, NUM_ACCESSES),
+            trace,
+        };
+        let mut recorded = [0; NUM_ACCESSES];
+        assert_eq!(NUM_ACCESSES, recording.next_indexes(&mut recorded).unwrap());
+        let trace = recording.trace.finish().unwrap();
+
+        // the same seed generates the same indexes
+        let mut expected = [0; NUM_ACCESSES];
+        let mut indexes = RandomIndexes::new(42, DATA_LEN, NUM_ACCESSES);
+        assert_eq!(NUM_ACCESSES, indexes.next_indexes(&mut expected).unwrap());
+        assert_eq!(expected, recorded);
+
+        let mut replay = AccessTraceReader::new(trace.as_slice()).unwrap();
+        let mut replayed = [0; NUM_ACCESSES];
+        assert_eq!(NUM_ACCESSES, replay.next_indexes(&mut replayed).unwrap());
+        assert_eq!(expected, replayed);
+    }
+
     #[test]
     fn test_mmap_aligned() {
         const ONE_GIB: usize = 1 << 30;
@@ -459,4 +2046,28 @@ mod test {
         // explicitly drop v: makes clippy happy because v is now used
         drop(v);
     }
+
+    #[test]
+    fn test_mmap_aligned_offset() {
+        const TWO_MIB: usize = 2 << 20;
+        const OFFSET: usize = 64 << 10;
+
+        let aligned_alloc =
+            MmapHugeMadviseAligned::new_offset_flags(TWO_MIB, OFFSET, TWO_MIB, MapFlags::empty())
+                .unwrap();
+        assert_eq!(OFFSET, aligned_alloc.get_mut() as usize % TWO_MIB);
+        let slice: &mut [u64];
+        unsafe {
+            slice = slice::from_raw_parts_mut(aligned_alloc.get_mut().cast::<u64>(), TWO_MIB / 8);
+        }
+        slice[0] = 0x42;
+        slice[slice.len() - 1] = 0x42;
+        drop(aligned_alloc);
+
+        let err = MmapHugeMadviseAligned::new_offset_flags(TWO_MIB, 1, TWO_MIB, MapFlags::empty());
+        assert_eq!(nix::errno::Errno::EINVAL, err.err().unwrap());
+        let err =
+            MmapHugeMadviseAligned::new_offset_flags(TWO_MIB, TWO_MIB, TWO_MIB, MapFlags::empty());
+        assert_eq!(nix::errno::Errno::EINVAL, err.err().unwrap());
+    }
 }
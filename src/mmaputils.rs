@@ -1,5 +1,8 @@
+use crate::Error;
+#[cfg(target_os = "linux")]
+use crate::NumaPolicy;
 use nix::sys::mman::{MapFlags, ProtFlags};
-use std::{ffi::c_void, num::NonZeroUsize, ptr::NonNull};
+use std::{ffi::c_void, num::NonZeroUsize, os::fd::AsFd, ptr::NonNull};
 
 /// Owns a memory region with mmap and calls munmap on drop.
 pub struct MmapOwner {
@@ -17,6 +20,13 @@ impl MmapOwner {
     pub const fn get_mut(&self) -> *mut c_void {
         self.mmap_pointer.as_ptr()
     }
+
+    /// Sets the NUMA memory policy for the region with `mbind(2)`. Call this before writing to
+    /// the region, since moving pages that are already present is slow.
+    #[cfg(target_os = "linux")]
+    pub fn set_numa_policy(&self, policy: &NumaPolicy) -> Result<(), Error> {
+        crate::numa::mbind(self.get_mut() as usize, self.size, policy)
+    }
 }
 
 impl Drop for MmapOwner {
@@ -41,13 +51,11 @@ pub struct MmapRegion {
 }
 
 impl MmapRegion {
-    // this is used by faultlatency; clippy doesn't find it?
-    #[allow(dead_code)]
-    pub fn new(size: usize) -> Result<Self, nix::errno::Errno> {
+    pub fn new(size: usize) -> Result<Self, Error> {
         Self::new_flags(size, MapFlags::empty())
     }
 
-    pub fn new_flags(size: usize, flags: MapFlags) -> Result<Self, nix::errno::Errno> {
+    pub fn new_flags(size: usize, flags: MapFlags) -> Result<Self, Error> {
         let mmap_pointer: NonNull<c_void>;
         let non_zero_size = NonZeroUsize::new(size).expect("BUG: size must be > 0");
         unsafe {
@@ -56,7 +64,36 @@ impl MmapRegion {
                 non_zero_size,
                 ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
                 MapFlags::MAP_ANONYMOUS | MapFlags::MAP_PRIVATE | flags,
-            )?;
+            )
+            .map_err(|errno| Error::Os {
+                call: "mmap",
+                errno,
+            })?;
+        }
+
+        Ok(Self {
+            region: MmapOwner::new(mmap_pointer, size),
+        })
+    }
+
+    /// Maps size bytes of the file fd with `MAP_SHARED`, so writes are visible to every process
+    /// that maps the same file. The mapping remains valid after fd is closed.
+    pub fn new_shared_fd<Fd: AsFd>(size: usize, fd: Fd, flags: MapFlags) -> Result<Self, Error> {
+        let mmap_pointer: NonNull<c_void>;
+        let non_zero_size = NonZeroUsize::new(size).expect("BUG: size must be > 0");
+        unsafe {
+            mmap_pointer = nix::sys::mman::mmap(
+                None,
+                non_zero_size,
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_SHARED | flags,
+                fd,
+                0,
+            )
+            .map_err(|errno| Error::Os {
+                call: "mmap",
+                errno,
+            })?;
         }
 
         Ok(Self {
@@ -64,17 +101,19 @@ impl MmapRegion {
         })
     }
 
-    // this is actually used by faultlatency; clippy doesn't find it?
-    #[allow(dead_code)]
     #[must_use]
     pub const fn get_mut(&self) -> *mut c_void {
         self.region.get_mut()
     }
 
-    // this is actually used by faultlatency; clippy doesn't find it?
-    #[allow(dead_code)]
     #[must_use]
     pub fn ptr_as_usize(&self) -> usize {
         self.region.get_mut() as usize
     }
+
+    /// Sets the NUMA memory policy for the region. See [`MmapOwner::set_numa_policy`].
+    #[cfg(target_os = "linux")]
+    pub fn set_numa_policy(&self, policy: &NumaPolicy) -> Result<(), Error> {
+        self.region.set_numa_policy(policy)
+    }
 }
@@ -1,20 +1,53 @@
-use crate::anyos_hugepages;
-use std::error::Error;
+use crate::anyos_hugepages::{HugepageAdvice, PageSizeBackend};
+use hugepagedemo::{Error, KernelFs, PageSizes};
+use nix::sys::mman::MapFlags;
 
 #[allow(clippy::unnecessary_wraps)]
-pub fn print_hugepage_setting_on_linux() -> Result<(), Box<dyn Error>> {
+pub fn print_hugepage_setting_on_linux(_fs: &KernelFs) -> Result<(), Error> {
     println!("not running on linux; no transparent hugepage setting to parse");
     Ok(())
 }
 
-pub fn madvise_hugepages_on_linux(_slice: &mut [u64]) {
+#[allow(clippy::unnecessary_wraps)]
+pub fn madvise_hugepages_on_linux(
+    _slice: &mut [u64],
+    _advice: HugepageAdvice,
+    _touch: bool,
+) -> Result<(), Error> {
     // Do nothing if not on linux
     println!("not running on linux; not calling madvise");
+    Ok(())
+}
+
+pub const fn map_populate_flags() -> MapFlags {
+    // MAP_POPULATE is Linux-specific; pages will be faulted in by the fill
+    MapFlags::empty()
+}
+
+#[allow(clippy::unnecessary_wraps)]
+pub fn madvise_populate_write(_slice: &mut [u64]) -> Result<(), Error> {
+    println!("not running on linux; not calling madvise(MADV_POPULATE_WRITE)");
+    Ok(())
 }
 
 #[allow(clippy::unnecessary_wraps)]
-pub fn read_page_size(_p: usize) -> Result<usize, std::io::Error> {
+pub fn read_page_size(
+    _fs: &KernelFs,
+    page_sizes: PageSizes,
+    _p: usize,
+) -> Result<(usize, PageSizeBackend), Error> {
     println!("not running on linux; assuming allocation size = default page size");
-    let page_size = anyos_hugepages::sysconf_page_size();
-    Ok(page_size)
+    Ok((page_sizes.base, PageSizeBackend::Sysconf))
+}
+
+#[allow(clippy::unnecessary_wraps)]
+pub fn set_thp_disable(_except_advised: bool) -> Result<(), Error> {
+    println!("not running on linux; not calling prctl(PR_SET_THP_DISABLE)");
+    Ok(())
+}
+
+#[allow(clippy::unnecessary_wraps)]
+pub fn set_cpu_affinity(_cpu: usize) -> Result<(), Error> {
+    println!("not running on linux; not calling sched_setaffinity");
+    Ok(())
 }
@@ -0,0 +1,383 @@
+use crate::{Error, KernelFs};
+use nix::errno::Errno;
+use std::str::FromStr;
+
+/// A NUMA memory policy, which selects the nodes that pages are allocated from. The string
+/// format is the same as `/proc/self/numa_maps`, like "bind:0" or "interleave:0-1,3".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NumaPolicy {
+    /// Use the process policy, which defaults to the node of the CPU that faults in the page.
+    Default,
+    /// Only allocate from these nodes.
+    Bind(Vec<u32>),
+    /// Allocate pages round-robin from these nodes.
+    Interleave(Vec<u32>),
+    /// Allocate from this node if possible, otherwise from any node.
+    Preferred(u32),
+}
+
+impl FromStr for NumaPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |message: String| Error::parse(format!("NUMA policy {s:?}"), message);
+        let (mode, nodes) = s.split_once(':').unwrap_or((s, ""));
+        let nodes = if nodes.is_empty() {
+            Vec::new()
+        } else {
+            parse_node_list(nodes).map_err(invalid)?
+        };
+        match (mode, &nodes[..]) {
+            ("default", []) => Ok(Self::Default),
+            ("bind", [_, ..]) => Ok(Self::Bind(nodes)),
+            ("interleave", [_, ..]) => Ok(Self::Interleave(nodes)),
+            ("preferred", [node]) => Ok(Self::Preferred(*node)),
+            _ => Err(invalid(
+                "must be default, bind:NODES, interleave:NODES, or preferred:NODE".to_string(),
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for NumaPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Default => write!(f, "default"),
+            Self::Bind(nodes) => write!(f, "bind:{}", node_list_string(nodes)),
+            Self::Interleave(nodes) => write!(f, "interleave:{}", node_list_string(nodes)),
+            Self::Preferred(node) => write!(f, "preferred:{node}"),
+        }
+    }
+}
+
+/// Parses a list of nodes like "0-1,3", and returns them sorted.
+fn parse_node_list(input: &str) -> Result<Vec<u32>, String> {
+    let parse_node = |node: &str| {
+        node.parse::<u32>()
+            .map_err(|err| format!("invalid node {node:?}: {err}"))
+    };
+    let mut nodes = Vec::new();
+    for range in input.split(',') {
+        let (first, last) = range.split_once('-').unwrap_or((range, range));
+        let (first, last) = (parse_node(first)?, parse_node(last)?);
+        if first > last {
+            return Err(format!("invalid node range {range:?}"));
+        }
+        nodes.extend(first..=last);
+    }
+    nodes.sort_unstable();
+    nodes.dedup();
+    Ok(nodes)
+}
+
+/// Returns nodes formatted like "0-1,3". This is the inverse of [`parse_node_list`].
+fn node_list_string(nodes: &[u32]) -> String {
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    for &node in nodes {
+        match ranges.last_mut() {
+            Some((_, last)) if *last + 1 == node => *last = node,
+            _ => ranges.push((node, node)),
+        }
+    }
+    ranges
+        .iter()
+        .map(|&(first, last)| {
+            if first == last {
+                first.to_string()
+            } else {
+                format!("{first}-{last}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Sets the NUMA memory policy for the len bytes at start, which must be page aligned, with
+/// `mbind(2)`. Pages that are already present are moved to follow the policy, if possible.
+#[cfg(target_os = "linux")]
+pub fn mbind(start: usize, len: usize, policy: &NumaPolicy) -> Result<(), Error> {
+    // MPOL_MF_MOVE from include/uapi/linux/mempolicy.h; not defined by libc
+    const MPOL_MF_MOVE: libc::c_uint = 1 << 1;
+    const BITS_PER_WORD: usize = libc::c_ulong::BITS as usize;
+
+    let (mode, nodes) = match policy {
+        NumaPolicy::Default => (libc::MPOL_DEFAULT, &[][..]),
+        NumaPolicy::Bind(nodes) => (libc::MPOL_BIND, &nodes[..]),
+        NumaPolicy::Interleave(nodes) => (libc::MPOL_INTERLEAVE, &nodes[..]),
+        NumaPolicy::Preferred(node) => (libc::MPOL_PREFERRED, std::slice::from_ref(node)),
+    };
+    let max_node = nodes.iter().max().map_or(0, |node| *node as usize + 1);
+    let mut node_mask: Vec<libc::c_ulong> = vec![0; max_node.div_ceil(BITS_PER_WORD)];
+    for &node in nodes {
+        node_mask[node as usize / BITS_PER_WORD] |= 1 << (node as usize % BITS_PER_WORD);
+    }
+
+    // the kernel ignores the last bit of maxnode, so pass one more than the mask's bits
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            start as *mut libc::c_void,
+            len as libc::c_ulong,
+            mode,
+            node_mask.as_ptr(),
+            (node_mask.len() * BITS_PER_WORD + 1) as libc::c_ulong,
+            MPOL_MF_MOVE,
+        )
+    };
+    Errno::result(result).map(drop).map_err(|errno| Error::Os {
+        call: "mbind",
+        errno,
+    })
+}
+
+/// One mapping from `/proc/self/numa_maps`: its memory policy, and how many of its pages are on
+/// each NUMA node.
+///
+/// See <https://www.kernel.org/doc/html/latest/admin-guide/mm/numa_memory_policy.html> and
+/// `numa(7)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NumaMapsMapping {
+    pub start: usize,
+    /// The memory policy, like "default", "bind:0-1", or "interleave:0-1".
+    pub policy: String,
+    /// The number of pages on each node from the `N<node>=` fields, sorted by node.
+    pub node_pages: Vec<(u32, usize)>,
+    /// True for hugetlb mappings, which have the `huge` flag.
+    pub huge: bool,
+    /// The size of the pages counted in `node_pages`, from `kernelpagesize_kB`.
+    pub kernel_page_size: Option<usize>,
+}
+
+impl NumaMapsMapping {
+    /// Returns the total number of pages on all nodes.
+    #[must_use]
+    pub fn pages(&self) -> usize {
+        self.node_pages.iter().map(|(_, pages)| pages).sum()
+    }
+}
+
+/// Returns the NUMA placement of the mappings of the current process. Requires a kernel with
+/// `CONFIG_NUMA`.
+pub fn read_numa_maps(fs: &KernelFs) -> Result<Vec<NumaMapsMapping>, Error> {
+    parse_numa_maps(&fs.read("/proc/self/numa_maps")?)
+}
+
+/// Parses the contents of `/proc/self/numa_maps`. Each line is one mapping, like
+/// `7f4c00000000 default anon=1048576 dirty=1048576 N0=1048576 kernelpagesize_kB=4`.
+pub fn parse_numa_maps(input: &[u8]) -> Result<Vec<NumaMapsMapping>, Error> {
+    const WHAT: &str = "/proc/self/numa_maps";
+
+    let mut mappings = Vec::new();
+    for line in String::from_utf8_lossy(input).lines() {
+        let mut fields = line.split_whitespace();
+        let (Some(start), Some(policy)) = (fields.next(), fields.next()) else {
+            continue;
+        };
+        let start = usize::from_str_radix(start, 16)
+            .map_err(|err| Error::parse(WHAT, format!("invalid address in {line:?}: {err}")))?;
+        let mut mapping = NumaMapsMapping {
+            start,
+            policy: policy.to_string(),
+            node_pages: Vec::new(),
+            huge: false,
+            kernel_page_size: None,
+        };
+        for field in fields {
+            if field == "huge" {
+                mapping.huge = true;
+                continue;
+            }
+            let Some((name, value)) = field.split_once('=') else {
+                continue;
+            };
+            let parse_value = || {
+                value
+                    .parse::<usize>()
+                    .map_err(|err| Error::parse(WHAT, format!("invalid {name} in {line:?}: {err}")))
+            };
+            if name == "kernelpagesize_kB" {
+                mapping.kernel_page_size = Some(parse_value()? * 1024);
+            } else if let Some(node) = name.strip_prefix('N')
+                && let Ok(node) = node.parse::<u32>()
+            {
+                mapping.node_pages.push((node, parse_value()?));
+            }
+            // other fields like anon= and dirty= are not needed; ignore them
+        }
+        mapping.node_pages.sort_unstable();
+        mappings.push(mapping);
+    }
+    Ok(mappings)
+}
+
+/// Returns the NUMA node of the page containing each address in addresses.
+///
+/// This uses `move_pages(2)` in query mode, which does not move them. The result for a page that
+/// is not present is its error, like ENOENT.
+#[cfg(target_os = "linux")]
+pub fn query_page_nodes(addresses: &[usize]) -> Result<Vec<Result<u32, Errno>>, Error> {
+    let mut status: Vec<libc::c_int> = vec![0; addresses.len()];
+    // nodes is null to query the current node instead of moving the pages
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_move_pages,
+            0 as libc::pid_t,
+            addresses.len() as libc::c_ulong,
+            addresses.as_ptr().cast::<*const libc::c_void>(),
+            std::ptr::null::<libc::c_int>(),
+            status.as_mut_ptr(),
+            0 as libc::c_int,
+        )
+    };
+    Errno::result(result).map_err(|errno| Error::Os {
+        call: "move_pages",
+        errno,
+    })?;
+    Ok(status
+        .into_iter()
+        .map(|node| u32::try_from(node).map_err(|_| Errno::from_raw(-node)))
+        .collect())
+}
+
+/// Returns true if err is from a NUMA system call that is not available: the kernel returns
+/// ENOSYS without `CONFIG_NUMA`, and seccomp filters like Docker's default profile return EPERM.
+#[must_use]
+pub fn is_numa_unsupported(err: &Error) -> bool {
+    matches!(
+        err,
+        Error::Os {
+            call: "mbind" | "move_pages",
+            errno: Errno::ENOSYS | Errno::EPERM,
+        }
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_numa_maps() {
+        const NUMA_MAPS: &[u8] = b"55b4eb043000 default file=/usr/bin/head mapped=2 N0=2 kernelpagesize_kB=4
+7f4c00000000 interleave:0-1 anon=1048576 dirty=1048576 N1=524288 N0=524288 kernelpagesize_kB=4
+7f4e00000000 bind:1 file=/memfd:hugepagedemo\\040(deleted) huge dirty=2048 N1=2048 kernelpagesize_kB=2048
+7ffd12345000 default
+";
+        let mappings = parse_numa_maps(NUMA_MAPS).unwrap();
+        assert_eq!(4, mappings.len());
+        assert_eq!(0x55b4_eb04_3000, mappings[0].start);
+        assert_eq!("default", mappings[0].policy);
+        assert_eq!(vec![(0, 2)], mappings[0].node_pages);
+        assert!(!mappings[0].huge);
+        assert_eq!(Some(4096), mappings[0].kernel_page_size);
+
+        assert_eq!("interleave:0-1", mappings[1].policy);
+        assert_eq!(vec![(0, 524_288), (1, 524_288)], mappings[1].node_pages);
+        assert_eq!(1_048_576, mappings[1].pages());
+
+        assert!(mappings[2].huge);
+        assert_eq!(Some(2 << 20), mappings[2].kernel_page_size);
+
+        // mappings with no pages have no counts
+        assert_eq!(0, mappings[3].pages());
+        assert_eq!(None, mappings[3].kernel_page_size);
+
+        assert!(parse_numa_maps(b"xyz default\n").is_err());
+        assert!(parse_numa_maps(b"7f4c00000000 default N0=x\n").is_err());
+    }
+
+    #[test]
+    fn test_numa_policy() {
+        for s in [
+            "default",
+            "bind:0",
+            "bind:0-1,3",
+            "interleave:0-3",
+            "preferred:1",
+        ] {
+            assert_eq!(s, NumaPolicy::from_str(s).unwrap().to_string());
+        }
+        assert_eq!(
+            NumaPolicy::Interleave(vec![0, 1, 2, 5]),
+            NumaPolicy::from_str("interleave:5,0-2,1").unwrap()
+        );
+        for s in [
+            "",
+            "bind",
+            "bind:",
+            "bind:x",
+            "bind:2-1",
+            "preferred:0-1",
+            "default:0",
+        ] {
+            assert!(NumaPolicy::from_str(s).is_err(), "s={s:?}");
+        }
+    }
+
+    #[test]
+    fn test_is_numa_unsupported() {
+        let os_error = |call, errno| Error::Os { call, errno };
+        assert!(is_numa_unsupported(&os_error("move_pages", Errno::ENOSYS)));
+        assert!(is_numa_unsupported(&os_error("mbind", Errno::EPERM)));
+        assert!(!is_numa_unsupported(&os_error("mbind", Errno::EINVAL)));
+        assert!(!is_numa_unsupported(&os_error("mmap", Errno::EPERM)));
+    }
+
+    #[test]
+    fn test_read_numa_maps_fixtures() {
+        let mappings = read_numa_maps(&KernelFs::fixture("x86_64")).unwrap();
+        let largest = mappings
+            .iter()
+            .max_by_key(|mapping| mapping.pages())
+            .unwrap();
+        assert_eq!(0x7fab_8b20_0000, largest.start);
+        assert_eq!(vec![(0, 1_048_576)], largest.node_pages);
+        assert_eq!(Some(4096), largest.kernel_page_size);
+
+        // the hugetlb mapping counts 512 MiB pages, interleaved across two nodes
+        let mappings = read_numa_maps(&KernelFs::fixture("synthetic-arm64-64k")).unwrap();
+        let hugetlb = mappings.iter().find(|mapping| mapping.huge).unwrap();
+        assert_eq!(vec![(0, 4), (1, 4)], hugetlb.node_pages);
+        assert_eq!(Some(512 << 20), hugetlb.kernel_page_size);
+
+        // kernels without CONFIG_NUMA do not have numa_maps
+        let err = read_numa_maps(&KernelFs::missing_fixture()).unwrap_err();
+        assert!(matches!(err, Error::Io(_)), "err={err}");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_query_page_nodes() {
+        let v = vec![0x42u64; 1024];
+        let nodes = match query_page_nodes(&[v.as_ptr() as usize]) {
+            Err(err) if is_numa_unsupported(&err) => {
+                println!("skipping: {err}");
+                return;
+            }
+            result => result.unwrap(),
+        };
+        assert!(nodes[0].is_ok(), "nodes={nodes:?}");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_mbind() {
+        let region = crate::MmapRegion::new(4096).unwrap();
+        match region.set_numa_policy(&NumaPolicy::Bind(vec![0])) {
+            Err(err) if is_numa_unsupported(&err) => {
+                println!("skipping: {err}");
+                return;
+            }
+            result => result.unwrap(),
+        }
+        unsafe { region.get_mut().cast::<u8>().write(1) };
+        let nodes = query_page_nodes(&[region.ptr_as_usize()]).unwrap();
+        assert_eq!(Ok(0), nodes[0]);
+
+        // a node that does not exist, in a node mask with more than one word
+        let err = region
+            .set_numa_policy(&NumaPolicy::Bind(vec![100]))
+            .unwrap_err();
+        assert!(matches!(err, Error::Os { call: "mbind", .. }), "err={err}");
+    }
+}
@@ -0,0 +1,157 @@
+use crate::{Error, KernelFs};
+use nix::unistd::SysconfVar;
+
+/// The base page size and the transparent huge page size of this machine, detected at runtime.
+///
+/// These depend on the architecture and kernel configuration: `x86_64` has 4 KiB base pages and
+/// 2 MiB huge pages, but arm64 kernels can use 64 KiB base pages with 512 MiB huge pages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageSizes {
+    /// The size of the pages mapped by the last level of the page table.
+    pub base: usize,
+    /// The size of a transparent huge page, which is mapped by one PMD (page middle directory)
+    /// entry.
+    pub huge: usize,
+}
+
+impl PageSizes {
+    /// Returns the page sizes of this machine, using [`sysconf_page_size`] for the base page size.
+    pub fn read(fs: &KernelFs) -> Result<Self, Error> {
+        Self::read_with_base(fs, sysconf_page_size())
+    }
+
+    /// Returns the page sizes for a kernel with base pages of base bytes. The huge page size is
+    /// read from `hpage_pmd_size`. If the kernel does not support transparent huge pages, it is
+    /// the size a PMD entry would map, so huge page aligned mappings are still aligned for
+    /// hugetlb pages of that size.
+    pub fn read_with_base(fs: &KernelFs, base: usize) -> Result<Self, Error> {
+        const HPAGE_PMD_SIZE_PATH: &str = "/sys/kernel/mm/transparent_hugepage/hpage_pmd_size";
+
+        let huge = match fs.read_usize(HPAGE_PMD_SIZE_PATH) {
+            Ok(huge) => huge,
+            Err(Error::Io(err)) if err.kind() == std::io::ErrorKind::NotFound => {
+                // each page table is one page of 8 byte entries
+                base * (base / 8)
+            }
+            Err(err) => return Err(err),
+        };
+        Ok(Self { base, huge })
+    }
+
+    /// Returns the size mapped by one PUD (page upper directory) entry: 1 GiB with 4 KiB base
+    /// pages, which is the size of the largest hugetlb pages on `x86_64`.
+    #[must_use]
+    pub const fn pud(&self) -> usize {
+        self.huge * (self.base / 8)
+    }
+}
+
+/// Returns the base page size from `sysconf(_SC_PAGESIZE)`.
+#[must_use]
+pub fn sysconf_page_size() -> usize {
+    let page_size = nix::unistd::sysconf(SysconfVar::PAGE_SIZE)
+        .expect("BUG: sysconf(_SC_PAGESIZE) must work")
+        .expect("BUG: page size must not be None");
+    assert!(page_size > 0, "page_size={page_size} must be > 0");
+    page_size as usize
+}
+
+/// Parses a page size like "4K", "2M", "1G", or a number of bytes. Suffixes are powers of 1024,
+/// and may optionally end in "iB" or "B", e.g. "2MiB".
+pub fn parse_page_size(input: &str) -> Result<usize, Error> {
+    let invalid = |message: &str| Error::parse(format!("page size {input:?}"), message);
+
+    let trimmed = input.trim();
+    let without_bytes = trimmed
+        .strip_suffix("iB")
+        .or_else(|| trimmed.strip_suffix('B'))
+        .unwrap_or(trimmed);
+    let (number, multiplier) = match without_bytes.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&without_bytes[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&without_bytes[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&without_bytes[..i], 1 << 30),
+        _ => (without_bytes, 1),
+    };
+    let value = number
+        .parse::<usize>()
+        .map_err(|err| invalid(&err.to_string()))?;
+    let size = value
+        .checked_mul(multiplier)
+        .ok_or_else(|| invalid("too large"))?;
+    if !size.is_power_of_two() {
+        return Err(invalid("must be a power of two"));
+    }
+    Ok(size)
+}
+
+/// Returns a short string for a page size, like "4K" or "2M". This is the inverse of
+/// [`parse_page_size`].
+#[must_use]
+pub fn page_size_string(size: usize) -> String {
+    const SUFFIXES: [(usize, &str); 3] = [(1 << 30, "G"), (1 << 20, "M"), (1 << 10, "K")];
+    for (multiplier, suffix) in SUFFIXES {
+        if size >= multiplier && size.is_multiple_of(multiplier) {
+            return format!("{}{suffix}", size / multiplier);
+        }
+    }
+    size.to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_page_size() {
+        assert_eq!(4096, parse_page_size("4K").unwrap());
+        assert_eq!(4096, parse_page_size("4096").unwrap());
+        assert_eq!(64 << 10, parse_page_size("64k").unwrap());
+        assert_eq!(2 << 20, parse_page_size("2MiB").unwrap());
+        assert_eq!(32 << 20, parse_page_size("32MB").unwrap());
+        assert_eq!(1 << 30, parse_page_size("1G").unwrap());
+
+        assert!(parse_page_size("").is_err());
+        assert!(parse_page_size("K").is_err());
+        assert!(parse_page_size("3K").is_err());
+        assert!(parse_page_size("2T").is_err());
+    }
+
+    #[test]
+    fn test_page_size_string() {
+        for s in ["4K", "64K", "2M", "32M", "1G", "16G"] {
+            assert_eq!(s, page_size_string(parse_page_size(s).unwrap()));
+        }
+        assert_eq!("512", page_size_string(512));
+    }
+
+    #[test]
+    fn test_page_sizes() {
+        let fixture =
+            |name, base| PageSizes::read_with_base(&KernelFs::fixture(name), base).unwrap();
+        let page_sizes = fixture("x86_64", 4 << 10);
+        assert_eq!(2 << 20, page_sizes.huge);
+        assert_eq!(1 << 30, page_sizes.pud());
+        assert_eq!(512 << 20, fixture("synthetic-arm64-64k", 64 << 10).huge);
+        // without transparent huge pages, this is computed from the base page size
+        assert_eq!(2 << 20, fixture("thp-never", 4 << 10).huge);
+        let without_thp = KernelFs::missing_fixture();
+        assert_eq!(
+            2 << 20,
+            PageSizes::read_with_base(&without_thp, 4 << 10)
+                .unwrap()
+                .huge
+        );
+        assert_eq!(
+            32 << 20,
+            PageSizes::read_with_base(&without_thp, 16 << 10)
+                .unwrap()
+                .huge
+        );
+
+        // the host may not support transparent huge pages, but must have a valid size
+        let page_sizes = PageSizes::read(&KernelFs::default()).unwrap();
+        assert_eq!(sysconf_page_size(), page_sizes.base);
+        assert!(page_sizes.huge.is_power_of_two());
+        assert!(page_sizes.huge > page_sizes.base);
+    }
+}
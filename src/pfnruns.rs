@@ -0,0 +1,165 @@
+/// How physically contiguous a virtual memory region is.
+///
+/// This is computed from the page frame number (PFN) of each of its base pages in
+/// `/proc/self/pagemap`. A run is a sequence of virtually consecutive pages that are also
+/// physically consecutive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PfnRuns {
+    pub present_pages: usize,
+    pub not_present_pages: usize,
+    /// The length of each run in base pages, in virtual address order.
+    pub run_lengths: Vec<usize>,
+    /// The number of distinct huge page sized, naturally aligned blocks of physical memory that
+    /// contain at least one page.
+    pub physical_blocks: usize,
+    /// The number of naturally aligned huge page sized virtual ranges where every page is present
+    /// and physically contiguous, so they can be mapped by a single huge page.
+    pub contiguous_huge_ranges: usize,
+    /// The number of `contiguous_huge_ranges` that also start at a naturally aligned physical
+    /// address. Huge pages are always aligned; unaligned ranges are contiguous by chance.
+    pub aligned_huge_ranges: usize,
+}
+
+impl PfnRuns {
+    /// Analyzes pfns, the page frame of each base page in a region, or None if it is not present.
+    /// `start_vpn` is the virtual page number of the first page, and `pages_per_huge_page` is
+    /// the huge page size in base pages, which must be a power of two.
+    #[must_use]
+    pub fn new(pfns: &[Option<u64>], start_vpn: u64, pages_per_huge_page: usize) -> Self {
+        assert!(pages_per_huge_page.is_power_of_two());
+        let block_pages = pages_per_huge_page as u64;
+
+        let mut run_lengths = Vec::new();
+        let mut physical_blocks = Vec::new();
+        let mut run_length = 0;
+        let mut previous_pfn = None;
+        for pfn in pfns {
+            let Some(pfn) = *pfn else {
+                if run_length > 0 {
+                    run_lengths.push(run_length);
+                }
+                run_length = 0;
+                previous_pfn = None;
+                continue;
+            };
+            physical_blocks.push(pfn / block_pages);
+            if run_length > 0 && previous_pfn.is_some_and(|previous| previous + 1 == pfn) {
+                run_length += 1;
+            } else {
+                if run_length > 0 {
+                    run_lengths.push(run_length);
+                }
+                run_length = 1;
+            }
+            previous_pfn = Some(pfn);
+        }
+        if run_length > 0 {
+            run_lengths.push(run_length);
+        }
+        physical_blocks.sort_unstable();
+        physical_blocks.dedup();
+
+        // the index of the first page in a naturally aligned virtual huge page range
+        let first_aligned = (block_pages - start_vpn % block_pages) as usize % pages_per_huge_page;
+        let mut contiguous_huge_ranges = 0;
+        let mut aligned_huge_ranges = 0;
+        for range in pfns
+            .get(first_aligned..)
+            .unwrap_or_default()
+            .chunks_exact(pages_per_huge_page)
+        {
+            let Some(head) = range[0] else {
+                continue;
+            };
+            let contiguous = range
+                .iter()
+                .zip(head..)
+                .all(|(pfn, expected)| *pfn == Some(expected));
+            if contiguous {
+                contiguous_huge_ranges += 1;
+                if head.is_multiple_of(block_pages) {
+                    aligned_huge_ranges += 1;
+                }
+            }
+        }
+
+        let present_pages = pfns.iter().filter(|pfn| pfn.is_some()).count();
+        Self {
+            present_pages,
+            not_present_pages: pfns.len() - present_pages,
+            run_lengths,
+            physical_blocks: physical_blocks.len(),
+            contiguous_huge_ranges,
+            aligned_huge_ranges,
+        }
+    }
+
+    /// Returns a histogram of the run lengths with power of two buckets, as (smallest length in
+    /// the bucket, number of runs, number of pages) for each non-empty bucket, from shortest to
+    /// longest. A bucket contains lengths from its smallest length up to twice that, exclusive.
+    #[must_use]
+    pub fn histogram(&self) -> Vec<(usize, usize, usize)> {
+        let mut buckets = Vec::<(usize, usize, usize)>::new();
+        for &length in &self.run_lengths {
+            let bucket_min = 1 << length.ilog2();
+            match buckets.binary_search_by_key(&bucket_min, |bucket| bucket.0) {
+                Ok(i) => {
+                    buckets[i].1 += 1;
+                    buckets[i].2 += length;
+                }
+                Err(i) => buckets.insert(i, (bucket_min, 1, length)),
+            }
+        }
+        buckets
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pfn_runs() {
+        // 4 pages per huge page, starting 2 pages before an aligned virtual address
+        let pfns = [
+            Some(100),
+            Some(101),
+            // aligned virtual range: contiguous and physically aligned
+            Some(8),
+            Some(9),
+            Some(10),
+            Some(11),
+            // aligned virtual range: contiguous but not physically aligned
+            Some(21),
+            Some(22),
+            Some(23),
+            Some(24),
+            // aligned virtual range: not present
+            None,
+            Some(40),
+            Some(30),
+            Some(31),
+            // partial range at the end is ignored
+            Some(32),
+        ];
+        let runs = PfnRuns::new(&pfns, 6, 4);
+        assert_eq!(14, runs.present_pages);
+        assert_eq!(1, runs.not_present_pages);
+        assert_eq!(vec![2, 4, 4, 1, 3], runs.run_lengths);
+        // 100 and 101 are in block 25; 8-11 are in block 2; 21-24 are in blocks 5 and 6;
+        // 40 is in block 10; 30-32 are in blocks 7 and 8
+        assert_eq!(7, runs.physical_blocks);
+        assert_eq!(2, runs.contiguous_huge_ranges);
+        assert_eq!(1, runs.aligned_huge_ranges);
+        assert_eq!(vec![(1, 1, 1), (2, 2, 5), (4, 2, 8)], runs.histogram());
+
+        // already aligned, and shorter than a huge page
+        let runs = PfnRuns::new(&pfns[..3], 8, 4);
+        assert_eq!(vec![2, 1], runs.run_lengths);
+        assert_eq!(0, runs.contiguous_huge_ranges);
+
+        let runs = PfnRuns::new(&[], 0, 512);
+        assert_eq!(0, runs.present_pages);
+        assert!(runs.histogram().is_empty());
+    }
+}
@@ -0,0 +1,135 @@
+use crate::{Error, KernelFs};
+
+/// Memory used by the current process, as reported by `/proc/self/status`.
+///
+/// Unlike the RSS reported by most tools, this separates anonymous and file-backed memory, and
+/// includes hugetlb pages and the memory used by page tables. All values are in bytes. See
+/// <https://www.kernel.org/doc/html/latest/filesystems/proc.html>.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ProcessMemory {
+    pub rss_anon: usize,
+    pub rss_file: usize,
+    pub rss_shmem: usize,
+    pub hugetlb_pages: usize,
+    pub vm_pte: usize,
+}
+
+impl ProcessMemory {
+    /// Returns the memory used by the current process.
+    #[cfg(target_os = "linux")]
+    pub fn read(fs: &KernelFs) -> Result<Self, Error> {
+        Self::parse(&fs.read("/proc/self/status")?)
+    }
+
+    /// Returns the memory used by the current process. Only RSS is available when not running
+    /// on Linux, which is reported as anonymous memory.
+    #[cfg(not(target_os = "linux"))]
+    pub fn read(_fs: &KernelFs) -> Result<Self, Error> {
+        let stats = memory_stats::memory_stats()
+            .ok_or_else(|| Error::Io(std::io::Error::other("memory_stats failed")))?;
+        Ok(Self {
+            rss_anon: stats.physical_mem,
+            ..Self::default()
+        })
+    }
+
+    /// Parses the contents of `/proc/self/status`. Fields missing from the input are zero, since
+    /// older kernels do not report all of them.
+    pub fn parse(input: &[u8]) -> Result<Self, Error> {
+        let mut memory = Self::default();
+        for line in input.split(|b| *b == b'\n') {
+            let Some(colon_index) = line.iter().position(|b| *b == b':') else {
+                continue;
+            };
+            let (key, value) = line.split_at(colon_index);
+            let field = match key {
+                b"RssAnon" => &mut memory.rss_anon,
+                b"RssFile" => &mut memory.rss_file,
+                b"RssShmem" => &mut memory.rss_shmem,
+                b"HugetlbPages" => &mut memory.hugetlb_pages,
+                b"VmPTE" => &mut memory.vm_pte,
+                _ => continue,
+            };
+            *field = parse_kib_value(&value[1..])
+                .map_err(|msg| Error::parse("/proc/self/status", msg))?;
+        }
+        Ok(memory)
+    }
+
+    /// Returns the (label, bytes) pairs for each field, in the order they appear in
+    /// `/proc/self/status`.
+    #[must_use]
+    pub const fn fields(&self) -> [(&'static str, usize); 5] {
+        [
+            ("RssAnon", self.rss_anon),
+            ("RssFile", self.rss_file),
+            ("RssShmem", self.rss_shmem),
+            ("HugetlbPages", self.hugetlb_pages),
+            ("VmPTE", self.vm_pte),
+        ]
+    }
+}
+
+/// Parses a value like `"\t   1234 kB"` and returns the number of bytes.
+pub fn parse_kib_value(value: &[u8]) -> Result<usize, String> {
+    let value_string = String::from_utf8_lossy(value);
+    let Some(kib_string) = value_string.trim().strip_suffix(" kB") else {
+        return Err(format!("value {value_string:?} does not end with kB"));
+    };
+    let kib = kib_string
+        .trim()
+        .parse::<usize>()
+        .map_err(|err| format!("invalid value {value_string:?}: {err}"))?;
+    Ok(kib * 1024)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        const STATUS: &[u8] = b"Name:\thugepagedemo
+VmPeak:\t 4202840 kB
+VmRSS:\t 4195716 kB
+RssAnon:\t 4194308 kB
+RssFile:\t    1408 kB
+RssShmem:\t       0 kB
+VmPTE:\t    8236 kB
+HugetlbPages:\t 1048576 kB
+Threads:\t1
+";
+        let memory = ProcessMemory::parse(STATUS).unwrap();
+        assert_eq!(
+            ProcessMemory {
+                rss_anon: 4_194_308 * 1024,
+                rss_file: 1408 * 1024,
+                rss_shmem: 0,
+                hugetlb_pages: 1_048_576 * 1024,
+                vm_pte: 8236 * 1024,
+            },
+            memory
+        );
+
+        // missing fields are zero
+        assert_eq!(
+            ProcessMemory::default(),
+            ProcessMemory::parse(b"Name:\tx\n").unwrap()
+        );
+
+        let err = ProcessMemory::parse(b"RssAnon:\t12 pages\n").unwrap_err();
+        assert!(err.to_string().contains("kB"), "err={err}");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_read() {
+        let memory = ProcessMemory::read(&KernelFs::default()).unwrap();
+        assert!(memory.rss_anon > 0);
+        assert!(memory.vm_pte > 0);
+
+        let memory = ProcessMemory::read(&KernelFs::fixture("synthetic-arm64-64k")).unwrap();
+        assert_eq!(1_048_960 * 1024, memory.rss_anon);
+        assert_eq!(4_194_304 * 1024, memory.hugetlb_pages);
+    }
+}
@@ -0,0 +1,203 @@
+use crate::procstatus::parse_kib_value;
+use crate::{Error, KernelFs};
+
+/// One mapping from `/proc/self/smaps`: its address range, and the memory counters for it. See
+/// <https://www.kernel.org/doc/html/latest/filesystems/proc.html>.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SmapsMapping {
+    pub start: usize,
+    pub end: usize,
+    /// The (name, bytes) pairs for every field reported in kB, like `Rss` or `AnonHugePages`.
+    fields: Vec<(String, usize)>,
+    /// The two letter flags from the `VmFlags` line, like "hg" for `MADV_HUGEPAGE`.
+    pub vm_flags: Vec<String>,
+}
+
+impl SmapsMapping {
+    /// Returns the value of a field in bytes, or None if the kernel does not report it.
+    #[must_use]
+    pub fn field(&self, name: &str) -> Option<usize> {
+        self.fields
+            .iter()
+            .find(|(field_name, _)| field_name == name)
+            .map(|(_, bytes)| *bytes)
+    }
+
+    #[must_use]
+    pub fn has_vm_flag(&self, flag: &str) -> bool {
+        self.vm_flags.iter().any(|vm_flag| vm_flag == flag)
+    }
+
+    /// Returns true if this mapping contains any address in [start, end).
+    #[must_use]
+    pub const fn overlaps(&self, start: usize, end: usize) -> bool {
+        self.start < end && start < self.end
+    }
+}
+
+/// Returns the mappings of the current process.
+pub fn read_smaps(fs: &KernelFs) -> Result<Vec<SmapsMapping>, Error> {
+    parse_smaps(&fs.read("/proc/self/smaps")?)
+}
+
+/// Returns the sum of field for the mappings that overlap [start, end). Mappings that extend
+/// outside the range are counted in full, so callers should pass the range of a whole mapping.
+#[must_use]
+pub fn sum_smaps_field(mappings: &[SmapsMapping], start: usize, end: usize, name: &str) -> usize {
+    mappings
+        .iter()
+        .filter(|mapping| mapping.overlaps(start, end))
+        .filter_map(|mapping| mapping.field(name))
+        .sum()
+}
+
+/// Parses the contents of `/proc/self/smaps`. Each mapping starts with a header line like
+/// `7f4c00000000-7f4d00000000 rw-p 00000000 00:00 0`, followed by one line per field.
+pub fn parse_smaps(input: &[u8]) -> Result<Vec<SmapsMapping>, Error> {
+    const WHAT: &str = "/proc/self/smaps";
+
+    let mut mappings = Vec::new();
+    for line in String::from_utf8_lossy(input).lines() {
+        if let Some((start, end)) = parse_header_range(line) {
+            mappings.push(SmapsMapping {
+                start,
+                end,
+                fields: Vec::new(),
+                vm_flags: Vec::new(),
+            });
+            continue;
+        }
+
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let Some(mapping) = mappings.last_mut() else {
+            return Err(Error::parse(
+                WHAT,
+                format!("field line {line:?} before the first mapping"),
+            ));
+        };
+        if name == "VmFlags" {
+            mapping.vm_flags = value.split_whitespace().map(str::to_string).collect();
+        } else if value.ends_with(" kB") {
+            let bytes = parse_kib_value(value.as_bytes()).map_err(|msg| Error::parse(WHAT, msg))?;
+            mapping.fields.push((name.to_string(), bytes));
+        }
+        // other fields like THPeligible are not sizes; ignore them
+    }
+    Ok(mappings)
+}
+
+/// Returns the address range from a mapping header line, or None if line is not a header.
+fn parse_header_range(line: &str) -> Option<(usize, usize)> {
+    let range = line.split_whitespace().next()?;
+    let (start, end) = range.split_once('-')?;
+    let start = usize::from_str_radix(start, 16).ok()?;
+    let end = usize::from_str_radix(end, 16).ok()?;
+    Some((start, end))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_smaps() {
+        const SMAPS: &[u8] = b"55d2c8e62000-55d2c8e86000 r-xp 00004000 fe:00 317771                     /usr/bin/grep
+Size:                144 kB
+Rss:                 144 kB
+AnonHugePages:         0 kB
+THPeligible:           0
+VmFlags: rd ex mr mw me
+7f4c00000000-7f4d00000000 rw-p 00000000 00:00 0
+Size:            4194304 kB
+KernelPageSize:        4 kB
+Rss:             4194304 kB
+AnonHugePages:   4192256 kB
+ShmemPmdMapped:        0 kB
+THPeligible:           1
+VmFlags: rd wr mr mw me ac sd hg
+";
+        let mappings = parse_smaps(SMAPS).unwrap();
+        assert_eq!(2, mappings.len());
+        assert_eq!(0x55d2_c8e6_2000, mappings[0].start);
+        assert_eq!(0x55d2_c8e8_6000, mappings[0].end);
+        assert_eq!(Some(144 * 1024), mappings[0].field("Rss"));
+        assert_eq!(None, mappings[0].field("KernelPageSize"));
+        assert!(!mappings[0].has_vm_flag("hg"));
+
+        assert_eq!(Some(4096), mappings[1].field("KernelPageSize"));
+        assert_eq!(Some(4_192_256 * 1024), mappings[1].field("AnonHugePages"));
+        assert!(mappings[1].has_vm_flag("hg"));
+
+        let start = 0x7f4c_0000_0000;
+        assert_eq!(
+            4_192_256 * 1024,
+            sum_smaps_field(&mappings, start, start + 4096, "AnonHugePages")
+        );
+        assert_eq!(0, sum_smaps_field(&mappings, 0, 4096, "AnonHugePages"));
+
+        assert!(parse_smaps(b"Rss: 4 kB\n").is_err());
+        assert!(parse_smaps(b"7f4c00000000-7f4d00000000 rw-p\nRss: 4 pages kB\n").is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_read_smaps() {
+        let mappings = read_smaps(&KernelFs::default()).unwrap();
+        let stack_value = 0u64;
+        let address = &raw const stack_value as usize;
+        let stack = mappings
+            .iter()
+            .find(|mapping| mapping.overlaps(address, address + 1))
+            .unwrap();
+        assert!(stack.field("Rss").unwrap() > 0);
+    }
+
+    #[test]
+    fn test_read_smaps_fixtures() {
+        const GIB: usize = 1 << 30;
+        let fixture = |name| read_smaps(&KernelFs::fixture(name)).unwrap();
+
+        let mappings = fixture("x86_64");
+        assert_eq!(31, mappings.len());
+        let thp = mappings
+            .iter()
+            .find(|mapping| mapping.has_vm_flag("hg"))
+            .unwrap();
+        assert_eq!(Some(4096), thp.field("KernelPageSize"));
+        assert_eq!(Some(4 * GIB), thp.field("Rss"));
+        // 55 of the 2048 huge pages were still base pages when it was captured
+        assert_eq!(Some(1993 << 21), thp.field("AnonHugePages"));
+
+        // base pages are 64 KiB, and transparent huge pages are 512 MiB
+        let mappings = fixture("synthetic-arm64-64k");
+        assert!(
+            mappings
+                .iter()
+                .all(|mapping| mapping.field("MMUPageSize") >= Some(64 << 10))
+        );
+        let hugetlb = mappings
+            .iter()
+            .find(|mapping| mapping.has_vm_flag("ht"))
+            .unwrap();
+        assert_eq!(Some(512 << 20), hugetlb.field("KernelPageSize"));
+        assert_eq!(Some(4 * GIB), hugetlb.field("Private_Hugetlb"));
+        assert_eq!(
+            GIB,
+            sum_smaps_field(&mappings, 0, usize::MAX, "AnonHugePages")
+        );
+
+        // the mapping is still advised with MADV_HUGEPAGE, but has no huge pages
+        let mappings = fixture("thp-never");
+        assert_eq!(
+            0,
+            sum_smaps_field(&mappings, 0, usize::MAX, "AnonHugePages")
+        );
+        let thp = mappings
+            .iter()
+            .find(|mapping| mapping.has_vm_flag("hg"))
+            .unwrap();
+        assert_eq!(Some(4 * GIB), thp.field("Rss"));
+    }
+}
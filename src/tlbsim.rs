@@ -0,0 +1,346 @@
+use crate::Error;
+use crate::pagesize::{page_size_string, parse_page_size};
+use std::str::FromStr;
+
+/// One TLB array: a set-associative cache of translations for one or more page sizes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlbArrayConfig {
+    pub entries: usize,
+    /// Associativity. Equal to entries for a fully associative array.
+    pub ways: usize,
+    pub page_sizes: Vec<usize>,
+}
+
+impl TlbArrayConfig {
+    /// Returns the amount of memory this array can translate with pages of `page_size`.
+    #[must_use]
+    pub fn reach(&self, page_size: usize) -> usize {
+        if self.page_sizes.contains(&page_size) {
+            self.entries * page_size
+        } else {
+            0
+        }
+    }
+}
+
+impl FromStr for TlbArrayConfig {
+    type Err = Error;
+
+    /// Parses `ENTRIESxWAYS:SIZE+SIZE`, e.g. "2048x16:4K+2M".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |message: String| Error::parse(format!("TLB array {s:?}"), message);
+        let (geometry, sizes) = s
+            .split_once(':')
+            .ok_or_else(|| invalid("must be ENTRIESxWAYS:SIZES".to_string()))?;
+        let (entries, ways) = geometry
+            .split_once('x')
+            .ok_or_else(|| invalid("must be ENTRIESxWAYS:SIZES".to_string()))?;
+        let entries = entries
+            .parse::<usize>()
+            .map_err(|err| invalid(format!("invalid entries: {err}")))?;
+        let ways = ways
+            .parse::<usize>()
+            .map_err(|err| invalid(format!("invalid ways: {err}")))?;
+        if entries == 0 {
+            return Err(invalid("entries must be greater than 0".to_string()));
+        }
+        if ways == 0 || entries % ways != 0 {
+            return Err(invalid(format!(
+                "entries={entries} must be a multiple of ways={ways}"
+            )));
+        }
+        let page_sizes = sizes
+            .split('+')
+            .map(parse_page_size)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self {
+            entries,
+            ways,
+            page_sizes,
+        })
+    }
+}
+
+impl std::fmt::Display for TlbArrayConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sizes = self
+            .page_sizes
+            .iter()
+            .map(|size| page_size_string(*size))
+            .collect::<Vec<_>>();
+        write!(f, "{}x{}:{}", self.entries, self.ways, sizes.join("+"))
+    }
+}
+
+/// A multi-level TLB. Each level contains one or more arrays that are looked up in parallel, such
+/// as separate L1 arrays for each page size, and a shared L2 "STLB".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlbConfig {
+    pub levels: Vec<Vec<TlbArrayConfig>>,
+}
+
+impl TlbConfig {
+    /// Approximately the data TLB of a recent Intel core (e.g. Golden Cove): a 64 entry 4K,
+    /// 32 entry 2M, and 8 entry 1G L1, and a 2048 entry shared 4K/2M and 1024 entry 1G L2.
+    pub const DEFAULT_SPEC: &str = "64x4:4K,32x4:2M,8x8:1G/2048x16:4K+2M,1024x8:1G";
+
+    /// Returns the page sizes supported by any array, sorted from smallest to largest.
+    #[must_use]
+    pub fn page_sizes(&self) -> Vec<usize> {
+        let mut sizes = self
+            .levels
+            .iter()
+            .flatten()
+            .flat_map(|array| array.page_sizes.iter().copied())
+            .collect::<Vec<_>>();
+        sizes.sort_unstable();
+        sizes.dedup();
+        sizes
+    }
+
+    /// Returns the amount of memory each level can translate with pages of `page_size`.
+    #[must_use]
+    pub fn reach(&self, page_size: usize) -> Vec<usize> {
+        self.levels
+            .iter()
+            .map(|arrays| arrays.iter().map(|array| array.reach(page_size)).sum())
+            .collect()
+    }
+}
+
+impl FromStr for TlbConfig {
+    type Err = Error;
+
+    /// Parses levels separated by '/', each containing arrays separated by ','. See
+    /// [`TlbArrayConfig::from_str`] for the array format.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let levels = s
+            .split('/')
+            .map(|level| {
+                level
+                    .split(',')
+                    .map(TlbArrayConfig::from_str)
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { levels })
+    }
+}
+
+impl std::fmt::Display for TlbConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, level) in self.levels.iter().enumerate() {
+            if i > 0 {
+                write!(f, "/")?;
+            }
+            for (j, array) in level.iter().enumerate() {
+                if j > 0 {
+                    write!(f, ",")?;
+                }
+                write!(f, "{array}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Set-associative array of virtual page numbers with LRU replacement.
+struct TlbArray {
+    ways: usize,
+    /// Each set is `ways` consecutive entries, ordered from most to least recently used.
+    entries: Vec<Option<usize>>,
+}
+
+impl TlbArray {
+    fn new(config: &TlbArrayConfig) -> Self {
+        Self {
+            ways: config.ways,
+            entries: vec![None; config.entries],
+        }
+    }
+
+    fn set(&mut self, page_number: usize) -> &mut [Option<usize>] {
+        let sets = self.entries.len() / self.ways;
+        let set_start = (page_number % sets) * self.ways;
+        &mut self.entries[set_start..set_start + self.ways]
+    }
+
+    /// Returns true if `page_number` is present, and makes it the most recently used.
+    fn lookup(&mut self, page_number: usize) -> bool {
+        let set = self.set(page_number);
+        let Some(way) = set.iter().position(|entry| *entry == Some(page_number)) else {
+            return false;
+        };
+        set[..=way].rotate_right(1);
+        true
+    }
+
+    /// Inserts `page_number` as the most recently used, evicting the least recently used.
+    fn insert(&mut self, page_number: usize) {
+        let set = self.set(page_number);
+        set.rotate_right(1);
+        set[0] = Some(page_number);
+    }
+}
+
+/// Hit and miss counts from [`TlbSimulator`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlbStats {
+    pub accesses: u64,
+    /// Number of accesses that hit at each level, and missed all lower levels.
+    pub level_hits: Vec<u64>,
+    /// Number of accesses that missed every level, and require a page table walk.
+    pub misses: u64,
+}
+
+impl TlbStats {
+    #[must_use]
+    pub fn miss_rate(&self) -> f64 {
+        self.misses as f64 / self.accesses as f64
+    }
+
+    #[must_use]
+    pub fn level_hit_rate(&self, level: usize) -> f64 {
+        self.level_hits[level] as f64 / self.accesses as f64
+    }
+}
+
+/// Predicts the TLB hits and misses for a stream of accesses to memory mapped with one page size.
+/// The TLB levels are inclusive: a miss fills every level that supports the page size.
+pub struct TlbSimulator {
+    page_size: usize,
+    /// The arrays at each level that can hold `page_size` translations.
+    levels: Vec<Vec<TlbArray>>,
+    stats: TlbStats,
+}
+
+impl TlbSimulator {
+    #[must_use]
+    pub fn new(config: &TlbConfig, page_size: usize) -> Self {
+        assert!(page_size.is_power_of_two());
+        let levels = config
+            .levels
+            .iter()
+            .map(|arrays| {
+                arrays
+                    .iter()
+                    .filter(|array| array.page_sizes.contains(&page_size))
+                    .map(TlbArray::new)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let stats = TlbStats {
+            accesses: 0,
+            level_hits: vec![0; levels.len()],
+            misses: 0,
+        };
+        Self {
+            page_size,
+            levels,
+            stats,
+        }
+    }
+
+    /// Simulates an access to the virtual address.
+    pub fn access(&mut self, address: usize) {
+        let page_number = address / self.page_size;
+        self.stats.accesses += 1;
+
+        let hit_level = self
+            .levels
+            .iter_mut()
+            .position(|arrays| arrays.iter_mut().any(|array| array.lookup(page_number)));
+        let fill_levels = if let Some(level) = hit_level {
+            self.stats.level_hits[level] += 1;
+            level
+        } else {
+            self.stats.misses += 1;
+            self.levels.len()
+        };
+        for arrays in &mut self.levels[..fill_levels] {
+            if let Some(array) = arrays.first_mut() {
+                array.insert(page_number);
+            }
+        }
+    }
+
+    #[must_use]
+    pub const fn stats(&self) -> &TlbStats {
+        &self.stats
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config = TlbConfig::from_str(TlbConfig::DEFAULT_SPEC).unwrap();
+        assert_eq!(2, config.levels.len());
+        assert_eq!(
+            TlbArrayConfig {
+                entries: 2048,
+                ways: 16,
+                page_sizes: vec![4 << 10, 2 << 20]
+            },
+            config.levels[1][0]
+        );
+        assert_eq!(TlbConfig::DEFAULT_SPEC, config.to_string());
+        assert_eq!(vec![4 << 10, 2 << 20, 1 << 30], config.page_sizes());
+        assert_eq!(vec![256 << 10, 8 << 20], config.reach(4 << 10));
+        assert_eq!(vec![64 << 20, 4 << 30], config.reach(2 << 20));
+
+        assert!(TlbConfig::from_str("64:4K").is_err());
+        assert!(TlbConfig::from_str("64x5:4K").is_err());
+        assert!(TlbConfig::from_str("64x0:4K").is_err());
+        assert!(TlbConfig::from_str("0x1:4K").is_err());
+        assert!(TlbConfig::from_str("64x4:3K").is_err());
+    }
+
+    #[test]
+    fn test_lru() {
+        // 1 set with 2 ways
+        let config = TlbConfig::from_str("2x2:4K").unwrap();
+        let mut sim = TlbSimulator::new(&config, 4096);
+        for page in [0, 1, 0, 2, 0, 1] {
+            sim.access(page * 4096);
+        }
+        // misses: 0, 1, 2 (evicts 1), 1 (evicts 2); hits: 0, 0
+        assert_eq!(
+            TlbStats {
+                accesses: 6,
+                level_hits: vec![2],
+                misses: 4
+            },
+            *sim.stats()
+        );
+    }
+
+    #[test]
+    fn test_levels() {
+        let config = TlbConfig::from_str("1x1:4K,1x1:2M/4x4:4K+2M").unwrap();
+
+        let mut sim = TlbSimulator::new(&config, 4096);
+        // miss, miss, L2 hit, L1 hit
+        for page in [0, 1, 0, 0] {
+            sim.access(page * 4096);
+        }
+        assert_eq!(vec![1, 1], sim.stats().level_hits);
+        assert_eq!(2, sim.stats().misses);
+
+        // all these addresses are in the same 2M page
+        let mut sim = TlbSimulator::new(&config, 2 << 20);
+        for page in [0, 1, 0, 0] {
+            sim.access(page * 4096);
+        }
+        assert_eq!(vec![3, 0], sim.stats().level_hits);
+        assert_eq!(1, sim.stats().misses);
+
+        // no array supports 1G pages: every access misses
+        let mut sim = TlbSimulator::new(&config, 1 << 30);
+        sim.access(0);
+        sim.access(0);
+        assert_eq!(2, sim.stats().misses);
+    }
+}
@@ -0,0 +1,28 @@
+#!/bin/sh
+# Copies the /proc and /sys files that hugepagedemo reads into a fixture directory.
+# Usage: sudo testdata/capture.sh <fixture directory> <pid>
+#
+# The /proc/self files are copied from process pid, which should be hugepagedemo while a
+# benchmark is running. Only the first 8 entries of /proc/kpageflags are copied, since the file
+# describes every page frame on the machine.
+set -eu
+
+out=$1
+pid=$2
+
+mkdir -p "$out/proc/self"
+for name in smaps numa_maps status; do
+	if [ -e "/proc/$pid/$name" ]; then
+		cat "/proc/$pid/$name" >"$out/proc/self/$name"
+	fi
+done
+dd if=/proc/kpageflags of="$out/proc/kpageflags" bs=8 count=8 2>/dev/null
+
+for dir in /sys/kernel/mm/hugepages /sys/kernel/mm/transparent_hugepage; do
+	if [ -d "$dir" ]; then
+		find "$dir" -type f -perm -u=r -not -path '*/stats/*' | while read -r path; do
+			mkdir -p "$out$(dirname "$path")"
+			cat "$path" >"$out$path"
+		done
+	fi
+done